@@ -0,0 +1,61 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::io::{File, Append, Write, IoResult};
+use libc;
+use clock;
+
+// One JSON object per line, so the log can be grepped or fed to jq as is.
+pub struct AuditLog {
+	file : Option<File>,
+}
+
+impl AuditLog {
+	pub fn disabled() -> AuditLog {
+		AuditLog { file : None }
+	}
+
+	pub fn open(path : &Path) -> IoResult<AuditLog> {
+		let file = try!(File::open_mode(path, Append, Write));
+		Ok(AuditLog { file : Some(file) })
+	}
+
+	pub fn record(&mut self, event : &str, fields : &[(&str, String)]) {
+		let file = match self.file {
+			Some(ref mut f) => f,
+			None => return,
+		};
+		let now = clock::now();
+		let pid = unsafe { libc::getpid() };
+		let mut line = format!("{{\"ts\":{}.{:09},\"pid\":{},\"event\":\"{}\"", now.sec, now.nsec, pid, escape(event));
+		for &(key, ref value) in fields.iter() {
+			line.push_str(format!(",\"{}\":\"{}\"", escape(key), escape(value.as_slice())).as_slice());
+		}
+		line.push('}');
+		// a broken audit log must never take the wrapped command down with it
+		let _ = file.write_line(line.as_slice());
+		let _ = file.flush();
+	}
+}
+
+fn escape(value : &str) -> String {
+	let mut out = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+			c => out.push(c),
+		}
+	}
+	out
+}
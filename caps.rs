@@ -0,0 +1,164 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::ascii::AsciiExt;
+
+// Capabilities are dropped from stdbuf itself just before the spawn: the
+// bounding set keeps the child from regaining them through file caps or
+// setuid binaries, and clearing effective/permitted/inheritable/ambient
+// leaves nothing for it to inherit.
+
+static NAMES: [&'static str, ..41] = [
+	"chown", "dac_override", "dac_read_search", "fowner", "fsetid", "kill",
+	"setgid", "setuid", "setpcap", "linux_immutable", "net_bind_service",
+	"net_broadcast", "net_admin", "net_raw", "ipc_lock", "ipc_owner",
+	"sys_module", "sys_rawio", "sys_chroot", "sys_ptrace", "sys_pacct",
+	"sys_admin", "sys_boot", "sys_nice", "sys_resource", "sys_time",
+	"sys_tty_config", "mknod", "lease", "audit_write", "audit_control",
+	"setfcap", "mac_override", "mac_admin", "syslog", "wake_alarm",
+	"block_suspend", "audit_read", "perfmon", "bpf", "checkpoint_restore",
+];
+
+#[deriving(Show)]
+pub struct CapSet {
+	pub caps : Vec<uint>,
+}
+
+impl CapSet {
+	// "all" or a comma separated list such as "net_admin,CAP_SYS_ADMIN"
+	pub fn parse(spec : &str) -> Result<CapSet, String> {
+		if spec == "all" {
+			return Ok(CapSet { caps : range(0, NAMES.len()).collect() });
+		}
+		let mut caps = Vec::new();
+		for item in spec.split(',') {
+			if item.is_empty() {
+				continue;
+			}
+			let lower = item.to_ascii_lower();
+			let name = if lower.as_slice().starts_with("cap_") { lower.as_slice().slice_from(4) } else { lower.as_slice() };
+			match NAMES.iter().position(|n| *n == name) {
+				Some(cap) => if !caps.contains(&cap) { caps.push(cap) },
+				None => return Err(format!("unknown capability '{}'", item)),
+			}
+		}
+		if caps.is_empty() {
+			return Err("empty capability list".to_string());
+		}
+		Ok(CapSet { caps : caps })
+	}
+
+	pub fn names(&self) -> String {
+		let names : Vec<&str> = self.caps.iter().map(|&c| NAMES[c]).collect();
+		names.connect(",")
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+	use libc::{c_int, c_ulong};
+
+	pub static PR_CAPBSET_DROP: c_int = 24;
+	pub static PR_CAP_AMBIENT: c_int = 47;
+	pub static PR_CAP_AMBIENT_LOWER: c_ulong = 3;
+	pub static VERSION_3: u32 = 0x20080522;
+
+	#[repr(C)]
+	pub struct Header {
+		pub version : u32,
+		pub pid : c_int,
+	}
+
+	#[repr(C)]
+	pub struct Data {
+		pub effective : u32,
+		pub permitted : u32,
+		pub inheritable : u32,
+	}
+
+	extern {
+		pub fn prctl(option : c_int, arg2 : c_ulong, arg3 : c_ulong, arg4 : c_ulong, arg5 : c_ulong) -> c_int;
+		pub fn capget(header : *mut Header, data : *mut Data) -> c_int;
+		pub fn capset(header : *mut Header, data : *const Data) -> c_int;
+	}
+}
+
+#[cfg(target_os = "linux")]
+pub fn drop_self(set : &CapSet) -> Result<(), String> {
+	use libc;
+	use std::os;
+	use self::sys::*;
+
+	let root = unsafe { libc::geteuid() } == 0;
+	for &cap in set.caps.iter() {
+		// without CAP_SETPCAP the bounding set cannot shrink; that only
+		// matters when we actually run privileged
+		if unsafe { prctl(PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } < 0 && root {
+			return Err(format!("cannot drop cap_{} from the bounding set: {}", NAMES[cap], os::last_os_error()));
+		}
+		unsafe { prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_LOWER, cap as libc::c_ulong, 0, 0) };
+	}
+
+	let mut header = Header { version : VERSION_3, pid : 0 };
+	let mut data = [Data { effective : 0, permitted : 0, inheritable : 0 }, Data { effective : 0, permitted : 0, inheritable : 0 }];
+	if unsafe { capget(&mut header, data.as_mut_ptr()) } < 0 {
+		return Err(format!("cannot read capabilities: {}", os::last_os_error()));
+	}
+	for &cap in set.caps.iter() {
+		let mask = !(1u32 << (cap % 32));
+		let word = &mut data[cap / 32];
+		word.effective &= mask;
+		word.permitted &= mask;
+		word.inheritable &= mask;
+	}
+	if unsafe { capset(&mut header, data.as_ptr()) } < 0 {
+		return Err(format!("cannot drop capabilities: {}", os::last_os_error()));
+	}
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_self(_set : &CapSet) -> Result<(), String> {
+	Err("dropping capabilities is only supported on Linux".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ascii::AsciiExt;
+	use super::{CapSet, NAMES};
+
+	#[test]
+	fn every_name_in_every_spelling() {
+		for (cap, name) in NAMES.iter().enumerate() {
+			let upper = name.to_ascii_upper();
+			for spec in [name.to_string(), upper.clone(), format!("cap_{}", name), format!("CAP_{}", upper)].iter() {
+				assert_eq!(CapSet::parse(spec.as_slice()).unwrap().caps, vec![cap]);
+			}
+		}
+	}
+
+	#[test]
+	fn lists_keep_order_without_repeats() {
+		let set = CapSet::parse("net_admin,CAP_SYS_ADMIN,,net_admin").unwrap();
+		assert_eq!(set.caps, vec![12, 21]);
+		assert_eq!(set.names(), "net_admin,sys_admin".to_string());
+	}
+
+	#[test]
+	fn all() {
+		assert_eq!(CapSet::parse("all").unwrap().caps.len(), NAMES.len());
+	}
+
+	#[test]
+	fn invalid_lists() {
+		for spec in ["", ",", "net_admin,bogus", "cap_", "ALL", "all,net_admin", "net admin"].iter() {
+			assert!(CapSet::parse(*spec).is_err(), "'{}' was accepted", spec);
+		}
+	}
+}
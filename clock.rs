@@ -0,0 +1,81 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Every time stdbuf writes out (prefix timestamps, audit records, progress
+// reports) comes from here, so --fake-clock can make runs reproducible.
+
+use time;
+use time::Timespec;
+
+#[deriving(Copy)]
+struct FakeClock {
+	start : Timespec,
+	rate : f64,
+	real_start_ns : u64,
+}
+
+// set once during option handling, before any other thread exists
+static mut FAKE: Option<FakeClock> = None;
+
+// "START[:RATE]" where START is seconds since the epoch or
+// YYYY-MM-DDTHH:MM:SS (UTC) and RATE is how many fake seconds pass per real
+// second, 0 freezing the clock; the default rate is 1
+pub fn parse_fake(spec : &str) -> Result<(Timespec, f64), String> {
+	// a date-time start has two colons of its own
+	let own_colons = if spec.contains("T") { 2 } else { 0 };
+	let colons = spec.chars().filter(|&c| c == ':').count();
+	let (start, rate) = match spec.rfind(':') {
+		Some(idx) if colons > own_colons => (spec.slice_to(idx), Some(spec.slice_from(idx + 1))),
+		_ => (spec, None),
+	};
+	let start = match from_str::<i64>(start) {
+		Some(sec) => Timespec::new(sec, 0),
+		None => match time::strptime(start, "%Y-%m-%dT%H:%M:%S") {
+			Ok(tm) => tm.to_timespec(),
+			Err(_) => return Err(format!("invalid --fake-clock start '{}'", start)),
+		},
+	};
+	let rate = match rate {
+		Some(r) => match from_str::<f64>(r) {
+			Some(r) if r >= 0.0 => r,
+			_ => return Err(format!("invalid --fake-clock rate '{}'", r)),
+		},
+		None => 1.0,
+	};
+	Ok((start, rate))
+}
+
+pub fn set_fake(start : Timespec, rate : f64) {
+	unsafe {
+		FAKE = Some(FakeClock { start : start, rate : rate, real_start_ns : time::precise_time_ns() });
+	}
+}
+
+fn fake_elapsed_ns(fake : &FakeClock) -> u64 {
+	((time::precise_time_ns() - fake.real_start_ns) as f64 * fake.rate) as u64
+}
+
+pub fn now() -> Timespec {
+	match unsafe { FAKE } {
+		Some(ref fake) => {
+			let elapsed = fake_elapsed_ns(fake);
+			let nsec = fake.start.nsec as u64 + elapsed % 1_000_000_000;
+			Timespec::new(fake.start.sec + (elapsed / 1_000_000_000 + nsec / 1_000_000_000) as i64, (nsec % 1_000_000_000) as i32)
+		},
+		None => time::get_time(),
+	}
+}
+
+// monotonic nanoseconds for measuring durations
+pub fn precise_time_ns() -> u64 {
+	match unsafe { FAKE } {
+		Some(ref fake) => fake_elapsed_ns(fake),
+		None => time::precise_time_ns(),
+	}
+}
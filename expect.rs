@@ -0,0 +1,174 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use libc::c_int;
+use std::io::stdio;
+use std::io::pipe::PipeStream;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::Thread;
+use regex::Regex;
+use pump;
+use pty;
+use shutdown;
+use shutdown::Pending;
+
+// prompts rarely end in a newline, so matching runs over a sliding window of
+// the most recent output rather than over complete lines
+static WINDOW: uint = 4096;
+
+#[deriving(Show, Clone)]
+pub struct SendRule {
+	pub pattern : Regex,
+	pub response : String,
+}
+
+impl SendRule {
+	// "PATTERN=RESPONSE"; the response is sent followed by a newline
+	pub fn parse(spec : &str) -> Result<SendRule, String> {
+		let idx = match spec.find('=') {
+			Some(idx) => idx,
+			None => return Err(format!("invalid --send-on rule '{}', expected PATTERN=RESPONSE", spec)),
+		};
+		let pattern = match Regex::new(spec.slice_to(idx)) {
+			Ok(re) => re,
+			Err(e) => return Err(format!("invalid --send-on pattern '{}': {}", spec.slice_to(idx), e)),
+		};
+		let mut response = spec.slice_from(idx + 1).to_string();
+		response.push('\n');
+		Ok(SendRule { pattern : pattern, response : response })
+	}
+}
+
+pub struct Responder {
+	rules : Vec<SendRule>,
+	pending : String,
+}
+
+impl Responder {
+	pub fn new(rules : Vec<SendRule>) -> Responder {
+		Responder { rules : rules, pending : String::new() }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.rules.is_empty()
+	}
+
+	// Returns the indices of the rules that fired, in output order. Text
+	// consumed by a match never triggers again.
+	pub fn feed(&mut self, chunk : &[u8]) -> Vec<uint> {
+		let mut fired = Vec::new();
+		if self.rules.is_empty() {
+			return fired;
+		}
+		self.pending.push_str(String::from_utf8_lossy(chunk).as_slice());
+		loop {
+			let mut first : Option<(uint, uint)> = None;
+			for (i, rule) in self.rules.iter().enumerate() {
+				if let Some((_, end)) = rule.pattern.find(self.pending.as_slice()) {
+					// an empty match would fire forever without consuming anything
+					if end == 0 {
+						continue;
+					}
+					match first {
+						Some((_, best)) if best <= end => {},
+						_ => first = Some((i, end)),
+					}
+				}
+			}
+			match first {
+				Some((i, end)) => {
+					fired.push(i);
+					self.pending = self.pending.as_slice().slice_from(end).to_string();
+				},
+				None => break,
+			}
+		}
+		if self.pending.len() > WINDOW {
+			let mut cut = self.pending.len() - WINDOW;
+			while !self.pending.as_slice().is_char_boundary(cut) {
+				cut += 1;
+			}
+			self.pending = self.pending.as_slice().slice_from(cut).to_string();
+		}
+		fired
+	}
+
+	pub fn rule(&self, idx : uint) -> &SendRule {
+		&self.rules[idx]
+	}
+}
+
+// --coalesce-stdin: a partial line or block is given to COMMAND as it is
+// if stdbuf panics, as it would be at the end of our stdin
+struct StdinOnPanic {
+	pending : Weak<Mutex<Vec<u8>>>,
+	target : Weak<Mutex<PipeStream>>,
+}
+
+impl Pending for StdinOnPanic {
+	fn flush_pending(&self) {
+		if let (Some(pending), Some(target)) = (self.pending.upgrade(), self.target.upgrade()) {
+			if let (Some(mut pending), Some(mut pipe)) = (pending.try_lock(), target.try_lock()) {
+				let rest = mem::replace(&mut *pending, Vec::new());
+				let _ = pipe.write(rest.as_slice()).and_then(|_| pipe.flush());
+			}
+		}
+	}
+}
+
+// With --send-on COMMAND's stdin is a pipe of ours rather than our own
+// stdin, so whatever we are given is pumped into it alongside the
+// responses. The pipe closes once the last handle to `target` is dropped:
+// when our stdin ends, unless the caller keeps one for the responses still
+// to come.
+//
+// Coalesced, what we are given reaches COMMAND only as whole lines or
+// blocks, however it was split on the way to us; a partial one is held
+// until it is complete or our stdin ends.
+//
+// With --pty, `target` is the terminal, which no closed handle ends;
+// `pty_control` is then given to pty::end_input when our stdin ends.
+pub fn forward_stdin(target : Arc<Mutex<PipeStream>>, mode : pump::Mode, pty_control : Option<c_int>) {
+	let pending = Arc::new(Mutex::new(Vec::new()));
+	if mode != pump::Mode::Unbuffered {
+		shutdown::flush_on_panic(box StdinOnPanic { pending : pending.downgrade(), target : target.downgrade() });
+	}
+	Thread::spawn(move || {
+		let mut input = stdio::stdin_raw();
+		let mut buf = [0u8, ..4096];
+		loop {
+			let n = match input.read(&mut buf) {
+				Ok(n) => n,
+				Err(_) => break,
+			};
+			let mut held = pending.lock();
+			held.push_all(buf.slice_to(n));
+			let end = pump::ready(&mode, held.as_slice());
+			if end == 0 {
+				continue;
+			}
+			let mut pipe = target.lock();
+			if pipe.write(held.slice_to(end)).and_then(|_| pipe.flush()).is_err() {
+				return;
+			}
+			*held = held.slice_from(end).to_vec();
+		}
+		let rest = mem::replace(&mut *pending.lock(), Vec::new());
+		if !rest.is_empty() {
+			let mut pipe = target.lock();
+			if pipe.write(rest.as_slice()).and_then(|_| pipe.flush()).is_err() {
+				return;
+			}
+		}
+		if let Some(control) = pty_control {
+			let _ = pty::end_input(control);
+		}
+	}).detach();
+}
@@ -0,0 +1,442 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::comm::{channel, Receiver, Select};
+use std::io::{BufferedReader, Command, IoResult, Timer};
+use std::io::pipe::PipeStream;
+use std::io::process::{Process, InheritFd};
+use std::mem;
+use std::thread::Thread;
+use std::time::Duration;
+use regex::Regex;
+
+// A stage of the output pipeline: takes one complete line and returns what
+// should be passed on in its place (nothing, the line, or several lines).
+pub trait LineFilter {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>>;
+
+	// called once at the end of the stream to release anything held back
+	fn finish(&mut self) -> Vec<Vec<u8>> {
+		Vec::new()
+	}
+}
+
+// The configurable stages, in their default order.
+#[deriving(Show, Clone, PartialEq)]
+pub enum FilterKind {
+	StripAnsi,
+	Redact,
+	Level,
+	Dedup,
+	Exec,
+	Prefix,
+	Group,
+}
+
+pub static DEFAULT_ORDER: [FilterKind, ..7] = [
+	FilterKind::StripAnsi,
+	FilterKind::Redact,
+	FilterKind::Level,
+	FilterKind::Dedup,
+	FilterKind::Exec,
+	FilterKind::Prefix,
+	FilterKind::Group,
+];
+
+impl FilterKind {
+	pub fn parse(name : &str) -> Option<FilterKind> {
+		match name {
+			"strip-ansi" => Some(FilterKind::StripAnsi),
+			"redact" => Some(FilterKind::Redact),
+			"level" => Some(FilterKind::Level),
+			"dedup" => Some(FilterKind::Dedup),
+			"exec" => Some(FilterKind::Exec),
+			"prefix" => Some(FilterKind::Prefix),
+			"group" => Some(FilterKind::Group),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			FilterKind::StripAnsi => "strip-ansi",
+			FilterKind::Redact => "redact",
+			FilterKind::Level => "level",
+			FilterKind::Dedup => "dedup",
+			FilterKind::Exec => "exec",
+			FilterKind::Prefix => "prefix",
+			FilterKind::Group => "group",
+		}
+	}
+
+	// "stdout=strip-ansi,redact,prefix"; the stream may be left out
+	pub fn parse_order(spec : &str) -> Result<Vec<FilterKind>, String> {
+		let list = match spec.find('=') {
+			Some(idx) => match spec.slice_to(idx) {
+				"stdout" => spec.slice_from(idx + 1),
+				stream => return Err(format!("cannot order filters for '{}', only stdout is relayed", stream)),
+			},
+			None => spec,
+		};
+		let mut order = Vec::new();
+		for name in list.split(',').filter(|n| !n.is_empty()) {
+			let kind = match FilterKind::parse(name) {
+				Some(kind) => kind,
+				None => return Err(format!("unknown filter '{}', expected one of strip-ansi, redact, level, dedup, exec, prefix, group", name)),
+			};
+			if order.contains(&kind) {
+				return Err(format!("filter '{}' is listed twice", name));
+			}
+			order.push(kind);
+		}
+		Ok(order)
+	}
+}
+
+// Lines go through the filters in the order they were added.
+pub struct Pipeline {
+	lines : LineBuffer,
+	// Send, as stderr's pipeline runs on a thread of its own
+	filters : Vec<Box<LineFilter + Send>>,
+}
+
+impl Pipeline {
+	pub fn new() -> Pipeline {
+		Pipeline { lines : LineBuffer::new(), filters : Vec::new() }
+	}
+
+	pub fn add(&mut self, filter : Box<LineFilter + Send>) {
+		self.filters.push(filter);
+	}
+
+	// without filters the output is passed through untouched, chunk by
+	// chunk, instead of being cut into lines
+	pub fn is_empty(&self) -> bool {
+		self.filters.is_empty()
+	}
+
+	pub fn push(&mut self, chunk : &[u8]) -> Vec<Vec<u8>> {
+		let mut lines = self.lines.push(chunk);
+		for i in range(0, self.filters.len()) {
+			lines = self.run_one(i, lines);
+		}
+		lines
+	}
+
+	pub fn finish(&mut self) -> Vec<Vec<u8>> {
+		let mut lines : Vec<Vec<u8>> = self.lines.finish().into_iter().collect();
+		for i in range(0, self.filters.len()) {
+			let mut next = self.run_one(i, lines);
+			next.extend(self.filters[i].finish().into_iter());
+			lines = next;
+		}
+		lines
+	}
+
+	fn run_one(&mut self, i : uint, lines : Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+		let mut out = Vec::new();
+		for line in lines.into_iter() {
+			out.extend(self.filters[i].push(line).into_iter());
+		}
+		out
+	}
+}
+
+// Cuts the relayed byte stream into lines, each keeping its '\n'.
+pub struct LineBuffer {
+	partial : Vec<u8>,
+}
+
+impl LineBuffer {
+	pub fn new() -> LineBuffer {
+		LineBuffer { partial : Vec::new() }
+	}
+
+	pub fn push(&mut self, chunk : &[u8]) -> Vec<Vec<u8>> {
+		let mut lines = Vec::new();
+		for &byte in chunk.iter() {
+			self.partial.push(byte);
+			if byte == b'\n' {
+				lines.push(mem::replace(&mut self.partial, Vec::new()));
+			}
+		}
+		lines
+	}
+
+	// whatever is left when the stream ends without a final newline
+	pub fn finish(&mut self) -> Option<Vec<u8>> {
+		if self.partial.is_empty() {
+			None
+		} else {
+			Some(mem::replace(&mut self.partial, Vec::new()))
+		}
+	}
+}
+
+// Removes terminal escape sequences (colors, cursor movement).
+pub struct StripAnsi {
+	escape : Regex,
+}
+
+impl StripAnsi {
+	pub fn new() -> StripAnsi {
+		StripAnsi { escape : Regex::new(r"\x1b(\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)|[@-Z\\-_])").unwrap() }
+	}
+}
+
+impl LineFilter for StripAnsi {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		if !line.contains(&0x1b) {
+			return vec![line];
+		}
+		let text = String::from_utf8_lossy(line.as_slice()).into_owned();
+		vec![self.escape.replace_all(text.as_slice(), "").into_bytes()]
+	}
+}
+
+// Like `uniq -c`, but the first line of a run goes out immediately and only
+// the repeats are summarised once the run ends, or every `window` repeats
+// for a child stuck in a loop that never ends.
+pub struct Dedup {
+	last : Option<Vec<u8>>,
+	repeats : uint,
+	window : Option<uint>,
+}
+
+impl Dedup {
+	pub fn new(window : Option<uint>) -> Dedup {
+		Dedup { last : None, repeats : 0, window : window }
+	}
+
+	fn summary(&mut self) -> Option<Vec<u8>> {
+		if self.repeats == 0 {
+			return None;
+		}
+		let line = format!("[previous line repeated {} more time{}]\n", self.repeats, if self.repeats == 1 { "" } else { "s" });
+		self.repeats = 0;
+		Some(line.into_bytes())
+	}
+}
+
+impl LineFilter for Dedup {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		let mut out = Vec::new();
+		if self.last.as_ref() == Some(&line) {
+			self.repeats += 1;
+			if Some(self.repeats) == self.window {
+				out.extend(self.summary().into_iter());
+			}
+			return out;
+		}
+		out.extend(self.summary().into_iter());
+		out.push(line.clone());
+		self.last = Some(line);
+		out
+	}
+
+	fn finish(&mut self) -> Vec<Vec<u8>> {
+		let out = self.summary().into_iter().collect();
+		self.last = None;
+		out
+	}
+}
+
+// Holds continuation lines (stack traces, wrapped messages) back until the
+// next line matching `start` begins a new record, then passes the finished
+// record on as a single unit so sinks never receive half a traceback.
+pub struct Grouper {
+	start : Regex,
+	record : Vec<u8>,
+}
+
+impl Grouper {
+	pub fn new(start : Regex) -> Grouper {
+		Grouper { start : start, record : Vec::new() }
+	}
+}
+
+impl LineFilter for Grouper {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		let starts = self.start.is_match(String::from_utf8_lossy(line.as_slice()).as_slice());
+		if starts && !self.record.is_empty() {
+			let done = mem::replace(&mut self.record, line);
+			return vec![done];
+		}
+		self.record.push_all(line.as_slice());
+		Vec::new()
+	}
+
+	fn finish(&mut self) -> Vec<Vec<u8>> {
+		if self.record.is_empty() {
+			Vec::new()
+		} else {
+			vec![mem::replace(&mut self.record, Vec::new())]
+		}
+	}
+}
+
+// Shortens lines for display to at most `max` terminal columns. Cuts fall
+// between grapheme clusters and double-width characters count twice, so a
+// truncated line never ends in half an emoji or CJK character.
+pub struct Truncator {
+	max : uint,
+	lines : LineBuffer,
+}
+
+impl Truncator {
+	pub fn new(max : uint) -> Truncator {
+		Truncator { max : max, lines : LineBuffer::new() }
+	}
+
+	pub fn push(&mut self, data : &[u8]) -> Vec<u8> {
+		let mut out = Vec::new();
+		for line in self.lines.push(data).iter() {
+			out.push_all(self.truncate(line.as_slice()).as_slice());
+		}
+		out
+	}
+
+	pub fn finish(&mut self) -> Vec<u8> {
+		match self.lines.finish() {
+			Some(line) => self.truncate(line.as_slice()),
+			None => Vec::new(),
+		}
+	}
+
+	fn truncate(&self, line : &[u8]) -> Vec<u8> {
+		// no character is wider than its UTF-8 encoding is long
+		if line.len() <= self.max {
+			return line.to_vec();
+		}
+		let newline = line.last() == Some(&b'\n');
+		let text = String::from_utf8_lossy(if newline { line.slice_to(line.len() - 1) } else { line });
+		if text.as_slice().width(false) <= self.max {
+			return line.to_vec();
+		}
+		// one column is kept for the marker
+		let mut out = String::new();
+		let mut columns = 0;
+		for grapheme in text.as_slice().graphemes(true) {
+			let width = grapheme.width(false);
+			if columns + width + 1 > self.max {
+				break;
+			}
+			out.push_str(grapheme);
+			columns += width;
+		}
+		out.push('…');
+		if newline {
+			out.push('\n');
+		}
+		out.into_bytes()
+	}
+}
+
+// Pipes the lines through an external command (`sh -c CMD`) that stays up
+// for the whole run. Its output is collected by a thread of its own, so a
+// filter that answers late or in bursts never fills its stdout pipe while
+// we are blocked writing to its stdin; the only backpressure left is the
+// filter not reading its input, which then simply slows the relay down.
+//
+// The relay only comes back here with the next chunk of output, so the
+// reply to a line is waited for briefly, or it would lag a line behind
+// while COMMAND is quiet. A filter that lets a line go unanswered is taken
+// not to reply line for line, and is no longer waited for.
+pub struct ExecFilter {
+	child : Process,
+	input : Option<PipeStream>,
+	output : Receiver<Vec<u8>>,
+	timer : Option<Timer>,
+	line_for_line : bool,
+}
+
+static REPLY_WAIT_MS: i64 = 50;
+
+impl ExecFilter {
+	pub fn spawn(command : &str) -> IoResult<ExecFilter> {
+		let mut child = try!(Command::new("sh").arg("-c").arg(command).stderr(InheritFd(2)).spawn());
+		let input = child.stdin.take();
+		let stdout = child.stdout.take().unwrap();
+		let (tx, rx) = channel();
+		Thread::spawn(move || {
+			let mut reader = BufferedReader::new(stdout);
+			loop {
+				match reader.read_until(b'\n') {
+					Ok(line) => if tx.send_opt(line).is_err() { break },
+					Err(_) => break,
+				}
+			}
+		}).detach();
+		Ok(ExecFilter { child : child, input : input, output : rx, timer : Timer::new().ok(), line_for_line : true })
+	}
+
+	// the first reply to the line just written, if it comes in time
+	fn await_reply(&mut self, out : &mut Vec<Vec<u8>>) {
+		let timeout = match self.timer {
+			Some(ref mut timer) => timer.oneshot(Duration::milliseconds(REPLY_WAIT_MS)),
+			None => return,
+		};
+		let answered = {
+			let sel = Select::new();
+			let mut reply = sel.handle(&self.output);
+			let mut expired = sel.handle(&timeout);
+			unsafe {
+				reply.add();
+				expired.add();
+			}
+			if sel.wait() == reply.id() {
+				if let Ok(line) = reply.recv_opt() {
+					out.push(line);
+				}
+				true
+			} else {
+				false
+			}
+		};
+		self.line_for_line = answered;
+	}
+
+	fn drain(&mut self, out : &mut Vec<Vec<u8>>) {
+		loop {
+			match self.output.try_recv() {
+				Ok(line) => out.push(line),
+				Err(_) => break,
+			}
+		}
+	}
+}
+
+impl LineFilter for ExecFilter {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		let mut out = Vec::new();
+		let failed = match self.input {
+			Some(ref mut input) => input.write(line.as_slice()).and_then(|_| input.flush()).is_err(),
+			None => false,
+		};
+		// a filter that exited early loses the rest of the output, as it
+		// would in a shell pipeline
+		if failed {
+			self.input = None;
+		} else if self.input.is_some() && self.line_for_line {
+			self.await_reply(&mut out);
+		}
+		self.drain(&mut out);
+		out
+	}
+
+	fn finish(&mut self) -> Vec<Vec<u8>> {
+		self.input = None;
+		let mut out = Vec::new();
+		for line in self.output.iter() {
+			out.push(line);
+		}
+		let _ = self.child.wait();
+		out
+	}
+}
@@ -0,0 +1,174 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Helpers for running stdbuf as a container entrypoint.
+
+use std::io::{BufferedReader, File};
+
+// KEY=VAL lines as in docker's --env-file; blank lines and '#' comments
+// are skipped and a value may be wrapped in single or double quotes
+pub fn read_env_file(path : &Path) -> Result<Vec<(String, String)>, String> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) => return Err(format!("cannot open {}: {}", path.display(), e)),
+	};
+	let mut vars = Vec::new();
+	for (n, line) in BufferedReader::new(file).lines().enumerate() {
+		let line = match line {
+			Ok(l) => l,
+			Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+		};
+		let line = line.as_slice().trim();
+		if line.is_empty() || line.starts_with("#") {
+			continue;
+		}
+		let line = if line.starts_with("export ") { line.slice_from(7).trim_left() } else { line };
+		let idx = match line.find('=') {
+			Some(idx) if idx > 0 => idx,
+			_ => return Err(format!("{}:{}: expected KEY=VALUE", path.display(), n + 1)),
+		};
+		let mut value = line.slice_from(idx + 1);
+		if value.len() >= 2 && (value.starts_with("\"") && value.ends_with("\"") || value.starts_with("'") && value.ends_with("'")) {
+			value = value.slice(1, value.len() - 1);
+		}
+		vars.push((line.slice_to(idx).trim_right().to_string(), value.to_string()));
+	}
+	Ok(vars)
+}
+
+// Splits a command line the way a shell would for the simple cases:
+// whitespace separates words, quotes group them, backslash escapes.
+pub fn split_words(line : &str) -> Result<Vec<String>, String> {
+	let mut words = Vec::new();
+	let mut word = String::new();
+	let mut in_word = false;
+	let mut quote : Option<char> = None;
+	let mut chars = line.chars();
+	loop {
+		let c = match chars.next() {
+			Some(c) => c,
+			None => break,
+		};
+		match quote {
+			Some(q) if c == q => quote = None,
+			Some('"') if c == '\\' => match chars.next() {
+				Some(n) => word.push(n),
+				None => return Err("trailing backslash".to_string()),
+			},
+			Some(_) => word.push(c),
+			None => match c {
+				'\'' | '"' => {
+					quote = Some(c);
+					in_word = true;
+				},
+				'\\' => match chars.next() {
+					Some(n) => {
+						word.push(n);
+						in_word = true;
+					},
+					None => return Err("trailing backslash".to_string()),
+				},
+				c if c.is_whitespace() => {
+					if in_word {
+						words.push(word);
+						word = String::new();
+						in_word = false;
+					}
+				},
+				c => {
+					word.push(c);
+					in_word = true;
+				},
+			},
+		}
+	}
+	if quote.is_some() {
+		return Err("unterminated quote".to_string());
+	}
+	if in_word {
+		words.push(word);
+	}
+	Ok(words)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+	use libc::{c_int, pid_t};
+
+	pub static P_ALL: c_int = 0;
+	pub static WEXITED: c_int = 4;
+	pub static WNOWAIT: c_int = 0x01000000;
+	pub static WNOHANG: c_int = 1;
+
+	// the SIGCHLD view of siginfo_t on 64-bit Linux
+	#[repr(C)]
+	pub struct Siginfo {
+		pub signo : c_int,
+		pub errno : c_int,
+		pub code : c_int,
+		pad : c_int,
+		pub pid : pid_t,
+		pub uid : u32,
+		pub status : c_int,
+		rest : [u8, ..100],
+	}
+
+	impl Siginfo {
+		pub fn new() -> Siginfo {
+			Siginfo { signo : 0, errno : 0, code : 0, pad : 0, pid : 0, uid : 0, status : 0, rest : [0u8, ..100] }
+		}
+	}
+
+	extern {
+		pub fn waitid(idtype : c_int, id : u32, info : *mut Siginfo, options : c_int) -> c_int;
+		pub fn waitpid(pid : pid_t, status : *mut c_int, options : c_int) -> pid_t;
+	}
+}
+
+// As PID 1 every orphan in the container becomes our child and has to be
+// reaped, without stealing the exit status of the command itself: the
+// reaper only peeks (WNOWAIT) and leaves the command's zombie to the
+// normal wait, stopping there. Whatever exits after that is collected by
+// reap_orphans().
+#[cfg(target_os = "linux")]
+pub fn start_reaper(child : ::libc::pid_t) {
+	use std::thread::Thread;
+	use self::sys::*;
+
+	Thread::spawn(move || {
+		loop {
+			let mut info = Siginfo::new();
+			if unsafe { waitid(P_ALL, 0, &mut info, WEXITED | WNOWAIT) } < 0 {
+				if ::std::os::errno() == ::libc::EINTR as uint {
+					continue;
+				}
+				break;
+			}
+			if info.pid == child {
+				break;
+			}
+			let mut status = 0;
+			unsafe { waitpid(info.pid, &mut status, 0) };
+		}
+	}).detach();
+}
+
+#[cfg(target_os = "linux")]
+pub fn reap_orphans() {
+	use self::sys::*;
+
+	let mut status = 0;
+	while unsafe { waitpid(-1, &mut status, WNOHANG) } > 0 {}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn start_reaper(_child : ::libc::pid_t) {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reap_orphans() {}
@@ -0,0 +1,193 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Landlock rules are inherited across fork and exec, so restricting stdbuf
+// itself right before the spawn confines the wrapped command as well.
+
+#[deriving(Show, PartialEq)]
+pub enum Access {
+	ReadOnly,
+	ReadWrite,
+}
+
+#[deriving(Show)]
+pub struct Rule {
+	pub access : Access,
+	pub path : Path,
+}
+
+#[deriving(Show)]
+pub struct Ruleset {
+	pub rules : Vec<Rule>,
+}
+
+impl Ruleset {
+	// "ro:/usr,rw:/tmp"
+	pub fn parse(spec : &str) -> Result<Ruleset, String> {
+		let mut rules = Vec::new();
+		for item in spec.split(',') {
+			if item.is_empty() {
+				continue;
+			}
+			let (kind, path) = match item.find(':') {
+				Some(idx) => (item.slice_to(idx), item.slice_from(idx + 1)),
+				None => return Err(format!("invalid landlock rule '{}', expected ro:PATH or rw:PATH", item)),
+			};
+			let access = match kind {
+				"ro" => Access::ReadOnly,
+				"rw" => Access::ReadWrite,
+				_ => return Err(format!("invalid landlock access '{}', expected ro or rw", kind)),
+			};
+			if path.is_empty() {
+				return Err(format!("missing path in landlock rule '{}'", item));
+			}
+			rules.push(Rule { access : access, path : Path::new(path) });
+		}
+		if rules.is_empty() {
+			return Err("empty landlock rule list".to_string());
+		}
+		Ok(Ruleset { rules : rules })
+	}
+}
+
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+pub static SUPPORTED: bool = true;
+
+#[cfg(not(all(target_os = "linux", feature = "landlock")))]
+pub static SUPPORTED: bool = false;
+
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+mod sys {
+	use libc::{c_int, c_long, c_void, size_t};
+
+	pub static CREATE_RULESET: c_long = 444;
+	pub static ADD_RULE: c_long = 445;
+	pub static RESTRICT_SELF: c_long = 446;
+	pub static RULE_PATH_BENEATH: c_int = 1;
+	pub static PR_SET_NO_NEW_PRIVS: c_int = 38;
+	pub static O_PATH: c_int = 0o10000000;
+	pub static O_CLOEXEC: c_int = 0o2000000;
+
+	// ABI v1 filesystem rights
+	pub static ACCESS_EXECUTE: u64 = 1 << 0;
+	pub static ACCESS_READ_FILE: u64 = 1 << 2;
+	pub static ACCESS_READ_DIR: u64 = 1 << 3;
+	pub static ACCESS_ALL: u64 = (1 << 13) - 1;
+
+	#[repr(C)]
+	pub struct RulesetAttr {
+		pub handled_access_fs : u64,
+	}
+
+	#[repr(C, packed)]
+	pub struct PathBeneathAttr {
+		pub allowed_access : u64,
+		pub parent_fd : c_int,
+	}
+
+	extern {
+		pub fn syscall(number : c_long, ...) -> c_long;
+		pub fn prctl(option : c_int, arg2 : c_long, arg3 : c_long, arg4 : c_long, arg5 : c_long) -> c_int;
+	}
+
+	pub fn attr_size<T>() -> size_t {
+		::std::mem::size_of::<T>() as size_t
+	}
+
+	pub fn attr_ptr<T>(attr : &T) -> *const c_void {
+		attr as *const T as *const c_void
+	}
+}
+
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+pub fn restrict_self(ruleset : &Ruleset) -> Result<(), String> {
+	use libc;
+	use std::os;
+	use std::c_str::ToCStr;
+	use self::sys::*;
+
+	let attr = RulesetAttr { handled_access_fs : ACCESS_ALL };
+	let ruleset_fd = unsafe { syscall(CREATE_RULESET, attr_ptr(&attr), attr_size::<RulesetAttr>(), 0u32) } as libc::c_int;
+	if ruleset_fd < 0 {
+		return Err(format!("landlock is not available: {}", os::last_os_error()));
+	}
+	for rule in ruleset.rules.iter() {
+		let fd = rule.path.with_c_str(|p| unsafe { libc::open(p, O_PATH | O_CLOEXEC, 0) });
+		if fd < 0 {
+			let err = os::last_os_error();
+			unsafe { libc::close(ruleset_fd) };
+			return Err(format!("cannot open {}: {}", rule.path.display(), err));
+		}
+		let allowed = match rule.access {
+			Access::ReadOnly => ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR,
+			Access::ReadWrite => ACCESS_ALL,
+		};
+		let beneath = PathBeneathAttr { allowed_access : allowed, parent_fd : fd };
+		let res = unsafe { syscall(ADD_RULE, ruleset_fd, RULE_PATH_BENEATH, attr_ptr(&beneath), 0u32) };
+		let err = os::last_os_error();
+		unsafe { libc::close(fd) };
+		if res < 0 {
+			unsafe { libc::close(ruleset_fd) };
+			return Err(format!("cannot add landlock rule for {}: {}", rule.path.display(), err));
+		}
+	}
+	let res = unsafe {
+		if prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+			-1
+		} else {
+			syscall(RESTRICT_SELF, ruleset_fd, 0u32)
+		}
+	};
+	let err = os::last_os_error();
+	unsafe { libc::close(ruleset_fd) };
+	if res < 0 {
+		return Err(format!("cannot enforce landlock ruleset: {}", err));
+	}
+	Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "landlock")))]
+pub fn restrict_self(_ruleset : &Ruleset) -> Result<(), String> {
+	Err("landlock support is not compiled in".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Ruleset, Access};
+
+	#[test]
+	fn rules_in_order() {
+		let set = Ruleset::parse("ro:/usr,rw:/tmp,ro:/etc/ssl").unwrap();
+		assert_eq!(set.rules.len(), 3);
+		assert_eq!(set.rules[0].access, Access::ReadOnly);
+		assert_eq!(set.rules[0].path, Path::new("/usr"));
+		assert_eq!(set.rules[1].access, Access::ReadWrite);
+		assert_eq!(set.rules[1].path, Path::new("/tmp"));
+		assert_eq!(set.rules[2].path, Path::new("/etc/ssl"));
+	}
+
+	#[test]
+	fn empty_items_are_skipped() {
+		let set = Ruleset::parse(",ro:/usr,,").unwrap();
+		assert_eq!(set.rules.len(), 1);
+	}
+
+	#[test]
+	fn path_may_hold_a_colon() {
+		let set = Ruleset::parse("rw:/srv/a:b").unwrap();
+		assert_eq!(set.rules[0].path, Path::new("/srv/a:b"));
+	}
+
+	#[test]
+	fn invalid_rules() {
+		for spec in ["", ",", "/usr", "ro", "ro:", "rx:/usr", "RO:/usr", ":/usr", "ro:/usr,rw"].iter() {
+			assert!(Ruleset::parse(*spec).is_err(), "'{}' was accepted", spec);
+		}
+	}
+}
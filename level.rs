@@ -0,0 +1,118 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use filter::LineFilter;
+use regex::Regex;
+
+#[deriving(Show, Clone, PartialEq, PartialOrd)]
+pub enum Level {
+	Trace,
+	Debug,
+	Info,
+	Warn,
+	Error,
+}
+
+impl Level {
+	pub fn parse(name : &str) -> Option<Level> {
+		match name {
+			"trace" => Some(Level::Trace),
+			"debug" => Some(Level::Debug),
+			"info" => Some(Level::Info),
+			"warn" | "warning" => Some(Level::Warn),
+			"error" => Some(Level::Error),
+			_ => None,
+		}
+	}
+}
+
+// "LEVEL=REGEX", as given to --level-regex
+#[deriving(Show, Clone)]
+pub struct LevelRule {
+	pub level : Level,
+	pub pattern : Regex,
+}
+
+impl LevelRule {
+	pub fn parse(spec : &str) -> Result<LevelRule, String> {
+		let idx = match spec.find('=') {
+			Some(idx) => idx,
+			None => return Err(format!("invalid level rule '{}', expected LEVEL=REGEX", spec)),
+		};
+		let level = match Level::parse(spec.slice_to(idx)) {
+			Some(level) => level,
+			None => return Err(format!("unknown level '{}'", spec.slice_to(idx))),
+		};
+		match Regex::new(spec.slice_from(idx + 1)) {
+			Ok(re) => Ok(LevelRule { level : level, pattern : re }),
+			Err(e) => Err(format!("invalid level pattern '{}': {}", spec.slice_from(idx + 1), e)),
+		}
+	}
+}
+
+// Drops lines below a minimum level. User rules are tried first, then the
+// usual spellings found in log lines; when several match, the leftmost
+// wins since the level normally comes right after the timestamp. Lines
+// without any marker (stack traces, wrapped messages) belong to the record
+// before them and share its fate.
+pub struct LevelFilter {
+	min : Level,
+	rules : Vec<LevelRule>,
+	builtin : Vec<LevelRule>,
+	current : Level,
+}
+
+impl LevelFilter {
+	pub fn new(min : Level, rules : Vec<LevelRule>) -> LevelFilter {
+		let builtin = vec![
+			(Level::Trace, r"(?i)\btrace\b"),
+			(Level::Debug, r"(?i)\b(debug|dbg)\b"),
+			(Level::Info, r"(?i)\b(info|notice)\b"),
+			(Level::Warn, r"(?i)\b(warn|warning)\b"),
+			(Level::Error, r"(?i)\b(error|err|fatal|crit|critical|panic|alert|emerg)\b"),
+		];
+		LevelFilter {
+			min : min,
+			rules : rules,
+			builtin : builtin.into_iter().map(|(level, re)| LevelRule { level : level, pattern : Regex::new(re).unwrap() }).collect(),
+			current : Level::Info,
+		}
+	}
+
+	fn classify(&self, line : &str) -> Option<Level> {
+		for set in [&self.rules, &self.builtin].iter() {
+			let mut best : Option<(uint, Level)> = None;
+			for rule in set.iter() {
+				if let Some((start, _)) = rule.pattern.find(line) {
+					match best {
+						Some((pos, _)) if pos <= start => {},
+						_ => best = Some((start, rule.level.clone())),
+					}
+				}
+			}
+			if let Some((_, level)) = best {
+				return Some(level);
+			}
+		}
+		None
+	}
+}
+
+impl LineFilter for LevelFilter {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		if let Some(level) = self.classify(String::from_utf8_lossy(line.as_slice()).as_slice()) {
+			self.current = level;
+		}
+		if self.current >= self.min {
+			vec![line]
+		} else {
+			Vec::new()
+		}
+	}
+}
@@ -0,0 +1,747 @@
+#![crate_name = "libstdbuf"]
+#![crate_type = "dylib"]
+
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Preloaded into the command by stdbuf. Before the command's main runs,
+// the streams named in _STDBUF_I, _STDBUF_O and _STDBUF_E are switched to
+// the buffering given there: "0" for none, "L" for lines, or a size in
+// bytes for a buffer of that size.
+//
+// For --fake-isatty, isatty() and tcgetattr() are replaced as well: the
+// descriptors listed in _STDBUF_TTY ("1,2") are reported as terminals,
+// every other one is asked about as usual.
+//
+// For --trace-io, write() and fwrite() are replaced too, and each call is
+// logged to the file named in _STDBUF_TRACE before being passed on.
+//
+// Streams the command opens itself later, through fopen(), fdopen() or
+// freopen(), get the mode given for their descriptor in _STDBUF_FD
+// ("3=L,5=4096"), or else the one in _STDBUF_ALL if set.
+//
+// stdbuf names the protocol it speaks in _STDBUF_PROTO; a library that does
+// not speak it leaves everything as it is rather than guess. Problems go to
+// the descriptor in _STDBUF_ERRFD, for stdbuf to report, or else to stderr.
+//
+// With _STDBUF_ONLY set, all of this happens only in a program whose name
+// matches that glob; anywhere else, in a wrapper script's shell say, the
+// library stays out of the way and leaves the variables to be inherited.
+//
+// _STDBUF_DEPTH counts the programs, this one included, still to be
+// reached by exec. The last of them takes the library out of the preload
+// variable, so whatever it runs starts as it would without stdbuf.
+
+extern crate libc;
+
+use libc::{c_char, c_int, c_void, size_t, ssize_t, timeval};
+use std::c_str::{CString, ToCStr};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicInt, AtomicUint, Ordering, INIT_ATOMIC_BOOL, INIT_ATOMIC_INT, INIT_ATOMIC_UINT};
+
+static _IOFBF: c_int = 0;
+static _IOLBF: c_int = 1;
+static _IONBF: c_int = 2;
+
+// the meaning of the variables above; raised on any incompatible change
+static PROTOCOL: &'static str = "1";
+
+static F_SETFD: c_int = 2;
+static FD_CLOEXEC: c_int = 1;
+
+#[cfg(target_os = "linux")]
+extern {
+	static stdin : *mut c_void;
+	static stdout : *mut c_void;
+	static stderr : *mut c_void;
+}
+
+#[cfg(target_os = "macos")]
+extern {
+	#[link_name = "__stdinp"]
+	static stdin : *mut c_void;
+	#[link_name = "__stdoutp"]
+	static stdout : *mut c_void;
+	#[link_name = "__stderrp"]
+	static stderr : *mut c_void;
+}
+
+extern {
+	fn setvbuf(stream : *mut c_void, buf : *mut c_void, mode : c_int, size : size_t) -> c_int;
+	fn getenv(name : *const c_char) -> *const c_char;
+	fn gettimeofday(tv : *mut timeval, tz : *mut c_void) -> c_int;
+	fn fileno(stream : *mut c_void) -> c_int;
+	fn fcntl(fd : c_int, cmd : c_int, ...) -> c_int;
+	fn unsetenv(name : *const c_char) -> c_int;
+	fn fflush(stream : *mut c_void) -> c_int;
+	fn flockfile(stream : *mut c_void);
+	fn funlockfile(stream : *mut c_void);
+	fn fnmatch(pattern : *const c_char, string : *const c_char, flags : c_int) -> c_int;
+	fn setenv(name : *const c_char, value : *const c_char, overwrite : c_int) -> c_int;
+}
+
+#[cfg(target_os = "linux")]
+static PRELOAD: &'static str = "LD_PRELOAD";
+#[cfg(target_os = "macos")]
+static PRELOAD: &'static str = "DYLD_INSERT_LIBRARIES";
+
+fn set_env(name : &str, value : &str) {
+	let (name, value) = (name.to_c_str(), value.to_c_str());
+	unsafe { setenv(name.as_ptr(), value.as_ptr(), 1) };
+}
+
+// Counts this exec generation off _STDBUF_DEPTH. Returns false when there
+// should have been none left, for a library preloaded again by hand.
+fn count_generation() -> bool {
+	let depth = match env("_STDBUF_DEPTH").and_then(|depth| from_str::<uint>(depth.as_slice())) {
+		Some(depth) => depth,
+		None => return true,
+	};
+	if depth == 0 {
+		return false;
+	}
+	set_env("_STDBUF_DEPTH", (depth - 1).to_string().as_slice());
+	if depth == 1 {
+		// the loader takes either separator
+		let libraries = env(PRELOAD).unwrap_or(String::new());
+		let kept : Vec<&str> = libraries.as_slice().split(|c : char| c == ':' || c == ' ')
+			.filter(|lib| !lib.is_empty() && !lib.contains("libstdbuf"))
+			.collect();
+		if kept.is_empty() {
+			let name = PRELOAD.to_c_str();
+			unsafe { unsetenv(name.as_ptr()) };
+		} else {
+			set_env(PRELOAD, kept.connect(":").as_slice());
+		}
+	}
+	true
+}
+
+// The C library's environment, not the standard library's view of it:
+// that takes a lock of its own, which a child forked from a threaded
+// program, calling fopen() before its exec, may find held forever.
+fn env(name : &str) -> Option<String> {
+	let name = name.to_c_str();
+	let value = unsafe { getenv(name.as_ptr()) };
+	if value.is_null() {
+		return None;
+	}
+	unsafe { CString::new(value, false) }.as_str().map(|value| value.to_string())
+}
+
+#[cfg(target_os = "linux")]
+extern {
+	static program_invocation_name : *const c_char;
+}
+
+#[cfg(target_os = "macos")]
+extern {
+	fn getprogname() -> *const c_char;
+}
+
+#[cfg(target_os = "linux")]
+fn program_name() -> *const c_char {
+	unsafe { program_invocation_name }
+}
+
+#[cfg(target_os = "macos")]
+fn program_name() -> *const c_char {
+	unsafe { getprogname() }
+}
+
+// whether this program is the one _STDBUF_ONLY asks for: 0 until known,
+// then 1 for yes and 2 for no
+static SELECTED: AtomicUint = INIT_ATOMIC_UINT;
+
+// A glob with a slash is matched against argv[0] as given, any other
+// against its last component, like the process name ps shows.
+fn selected() -> bool {
+	match SELECTED.load(Ordering::SeqCst) {
+		1 => return true,
+		2 => return false,
+		_ => {},
+	}
+	let glob = match env("_STDBUF_ONLY") {
+		Some(glob) => glob,
+		None => {
+			SELECTED.store(1, Ordering::SeqCst);
+			return true;
+		}
+	};
+	let name = program_name();
+	let matched = !name.is_null() && {
+		let full = unsafe { CString::new(name, false) };
+		let bytes = full.as_bytes_no_nul();
+		let subject = if glob.as_slice().contains_char('/') {
+			bytes
+		} else {
+			match bytes.iter().rposition(|&b| b == b'/') {
+				Some(idx) => bytes.slice_from(idx + 1),
+				None => bytes,
+			}
+		};
+		let pattern = glob.to_c_str();
+		let subject = subject.to_c_str();
+		unsafe { fnmatch(pattern.as_ptr(), subject.as_ptr(), 0) == 0 }
+	};
+	SELECTED.store(if matched { 1 } else { 2 }, Ordering::SeqCst);
+	matched
+}
+
+// _STDBUF_ERRFD, once read: 0 until then or without one
+static ERR_FD: AtomicInt = INIT_ATOMIC_INT;
+
+fn report(msg : &str) {
+	let fd = ERR_FD.load(Ordering::SeqCst);
+	let line = if fd > 0 { format!("{}\n", msg) } else { format!("libstdbuf: {}\n", msg) };
+	let fd = if fd > 0 { fd as c_int } else { 2 };
+	unsafe { libc::write(fd, line.as_ptr() as *const c_void, line.len() as size_t) };
+}
+
+// the --trace-io log: 0 until first used, -1 when there is none
+static TRACE_FD: AtomicInt = INIT_ATOMIC_INT;
+
+type WriteFn = extern fn(c_int, *const c_void, size_t) -> ssize_t;
+
+fn trace_fd() -> c_int {
+	let fd = TRACE_FD.load(Ordering::SeqCst);
+	if fd != 0 {
+		return fd as c_int;
+	}
+	let path = unsafe { getenv(b"_STDBUF_TRACE\0".as_ptr() as *const c_char) };
+	let opened = if path.is_null() || !selected() {
+		-1
+	} else {
+		unsafe { libc::open(path, libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT, 0o644) }
+	};
+	// another thread may have got there first
+	let fd = TRACE_FD.compare_and_swap(0, opened as int, Ordering::SeqCst);
+	if fd != 0 {
+		if opened >= 0 {
+			unsafe { libc::close(opened) };
+		}
+		return fd as c_int;
+	}
+	opened
+}
+
+// One line per call, "SECONDS.MICROS PID CALL fd=N bytes=N", written with
+// `real`: the C library's write, never ours, which would trace itself.
+fn trace(call : &str, fd : c_int, bytes : size_t, real : WriteFn) {
+	let log = trace_fd();
+	if log < 0 || fd == log {
+		return;
+	}
+	let mut now = timeval { tv_sec : 0, tv_usec : 0 };
+	unsafe { gettimeofday(&mut now, ptr::null_mut()) };
+	let line = format!("{}.{:06} {} {} fd={} bytes={}\n", now.tv_sec, now.tv_usec, unsafe { libc::getpid() }, call, fd, bytes);
+	real(log, line.as_ptr() as *const c_void, line.len() as size_t);
+}
+
+// Read on every call rather than once: other libraries' constructors may
+// ask before ours has run. The C library's getenv keeps it allocation-free.
+fn faked(fd : c_int) -> bool {
+	let value = unsafe { getenv(b"_STDBUF_TTY\0".as_ptr() as *const c_char) };
+	if value.is_null() || !selected() {
+		return false;
+	}
+	let list = unsafe { CString::new(value, false) };
+	match list.as_str() {
+		Some(list) => list.split(',').any(|n| from_str::<c_int>(n) == Some(fd)),
+		None => false,
+	}
+}
+
+// what tcgetattr() reports for a faked terminal: all flags clear, which
+// reads as a raw terminal to anyone who looks
+#[cfg(target_os = "linux")]
+static TERMIOS_SIZE: uint = 60;
+#[cfg(target_os = "macos")]
+static TERMIOS_SIZE: uint = 72;
+
+fn fake_tcgetattr(termios : *mut u8) -> c_int {
+	unsafe { ptr::zero_memory(termios, TERMIOS_SIZE) };
+	0
+}
+
+// With LD_PRELOAD our definitions come first in the global lookup, and the
+// C library's own are found with RTLD_NEXT.
+#[cfg(target_os = "linux")]
+mod interpose {
+	use libc::{c_char, c_int, c_void, size_t, ssize_t};
+	use std::sync::atomic::{AtomicUint, Ordering, INIT_ATOMIC_UINT};
+	use super::{faked, fake_tcgetattr, fileno, opened, trace, WriteFn};
+
+	static RTLD_NEXT: *mut c_void = -1i as *mut c_void;
+
+	// looked up once: write() is called far too often for dlsym each time
+	static REAL_WRITE: AtomicUint = INIT_ATOMIC_UINT;
+	static REAL_FWRITE: AtomicUint = INIT_ATOMIC_UINT;
+	static REAL_FOPEN: AtomicUint = INIT_ATOMIC_UINT;
+	static REAL_FOPEN64: AtomicUint = INIT_ATOMIC_UINT;
+	static REAL_FDOPEN: AtomicUint = INIT_ATOMIC_UINT;
+	static REAL_FREOPEN: AtomicUint = INIT_ATOMIC_UINT;
+
+	#[link(name = "dl")]
+	extern {
+		fn dlsym(handle : *mut c_void, symbol : *const c_char) -> *mut c_void;
+	}
+
+	fn next(symbol : &[u8], cache : &AtomicUint) -> *mut c_void {
+		let cached = cache.load(Ordering::SeqCst);
+		if cached != 0 {
+			return cached as *mut c_void;
+		}
+		let found = unsafe { dlsym(RTLD_NEXT, symbol.as_ptr() as *const c_char) };
+		cache.store(found as uint, Ordering::SeqCst);
+		found
+	}
+
+	fn real_write() -> WriteFn {
+		unsafe { ::std::mem::transmute(next(b"write\0", &REAL_WRITE)) }
+	}
+
+	#[no_mangle]
+	pub extern fn write(fd : c_int, buf : *const c_void, count : size_t) -> ssize_t {
+		let real = real_write();
+		trace("write", fd, count, real);
+		real(fd, buf, count)
+	}
+
+	// glibc's stdio flushes through its own internal write, which never
+	// reaches the one above, so buffered output is seen here instead
+	#[no_mangle]
+	pub extern fn fwrite(ptr : *const c_void, size : size_t, nmemb : size_t, stream : *mut c_void) -> size_t {
+		let real : extern fn(*const c_void, size_t, size_t, *mut c_void) -> size_t =
+			unsafe { ::std::mem::transmute(next(b"fwrite\0", &REAL_FWRITE)) };
+		trace("fwrite", unsafe { fileno(stream) }, size * nmemb, real_write());
+		real(ptr, size, nmemb, stream)
+	}
+
+	#[no_mangle]
+	pub extern fn fopen(path : *const c_char, mode : *const c_char) -> *mut c_void {
+		let real : extern fn(*const c_char, *const c_char) -> *mut c_void =
+			unsafe { ::std::mem::transmute(next(b"fopen\0", &REAL_FOPEN)) };
+		opened(real(path, mode))
+	}
+
+	// what fopen() is called as in programs built with large file support
+	#[no_mangle]
+	pub extern fn fopen64(path : *const c_char, mode : *const c_char) -> *mut c_void {
+		let real : extern fn(*const c_char, *const c_char) -> *mut c_void =
+			unsafe { ::std::mem::transmute(next(b"fopen64\0", &REAL_FOPEN64)) };
+		opened(real(path, mode))
+	}
+
+	#[no_mangle]
+	pub extern fn fdopen(fd : c_int, mode : *const c_char) -> *mut c_void {
+		let real : extern fn(c_int, *const c_char) -> *mut c_void =
+			unsafe { ::std::mem::transmute(next(b"fdopen\0", &REAL_FDOPEN)) };
+		opened(real(fd, mode))
+	}
+
+	#[no_mangle]
+	pub extern fn freopen(path : *const c_char, mode : *const c_char, stream : *mut c_void) -> *mut c_void {
+		let real : extern fn(*const c_char, *const c_char, *mut c_void) -> *mut c_void =
+			unsafe { ::std::mem::transmute(next(b"freopen\0", &REAL_FREOPEN)) };
+		opened(real(path, mode, stream))
+	}
+
+	#[no_mangle]
+	pub extern fn isatty(fd : c_int) -> c_int {
+		if faked(fd) {
+			return 1;
+		}
+		let real = unsafe { dlsym(RTLD_NEXT, b"isatty\0".as_ptr() as *const c_char) };
+		if real.is_null() {
+			return 0;
+		}
+		let real : extern fn(c_int) -> c_int = unsafe { ::std::mem::transmute(real) };
+		real(fd)
+	}
+
+	#[no_mangle]
+	pub extern fn tcgetattr(fd : c_int, termios : *mut u8) -> c_int {
+		if faked(fd) {
+			return fake_tcgetattr(termios);
+		}
+		let real = unsafe { dlsym(RTLD_NEXT, b"tcgetattr\0".as_ptr() as *const c_char) };
+		if real.is_null() {
+			return -1;
+		}
+		let real : extern fn(c_int, *mut u8) -> c_int = unsafe { ::std::mem::transmute(real) };
+		real(fd, termios)
+	}
+}
+
+// dyld has no global lookup to come first in, since every image binds to
+// the library it was linked against; instead it rewires the pairs listed
+// in __DATA,__interpose, everywhere but in this library itself, so the
+// originals can simply be called from here.
+#[cfg(target_os = "macos")]
+mod interpose {
+	use libc::{c_char, c_int, c_void, size_t, ssize_t};
+	use super::{faked, fake_tcgetattr, fileno, opened, trace};
+
+	extern {
+		fn isatty(fd : c_int) -> c_int;
+		fn tcgetattr(fd : c_int, termios : *mut u8) -> c_int;
+		fn write(fd : c_int, buf : *const c_void, count : size_t) -> ssize_t;
+		fn fwrite(ptr : *const c_void, size : size_t, nmemb : size_t, stream : *mut c_void) -> size_t;
+		fn fopen(path : *const c_char, mode : *const c_char) -> *mut c_void;
+		fn fdopen(fd : c_int, mode : *const c_char) -> *mut c_void;
+		fn freopen(path : *const c_char, mode : *const c_char, stream : *mut c_void) -> *mut c_void;
+	}
+
+	extern fn real_write(fd : c_int, buf : *const c_void, count : size_t) -> ssize_t {
+		unsafe { write(fd, buf, count) }
+	}
+
+	extern fn stdbuf_write(fd : c_int, buf : *const c_void, count : size_t) -> ssize_t {
+		trace("write", fd, count, real_write);
+		unsafe { write(fd, buf, count) }
+	}
+
+	extern fn stdbuf_fwrite(ptr : *const c_void, size : size_t, nmemb : size_t, stream : *mut c_void) -> size_t {
+		trace("fwrite", unsafe { fileno(stream) }, size * nmemb, real_write);
+		unsafe { fwrite(ptr, size, nmemb, stream) }
+	}
+
+	extern fn stdbuf_fopen(path : *const c_char, mode : *const c_char) -> *mut c_void {
+		opened(unsafe { fopen(path, mode) })
+	}
+
+	extern fn stdbuf_fdopen(fd : c_int, mode : *const c_char) -> *mut c_void {
+		opened(unsafe { fdopen(fd, mode) })
+	}
+
+	extern fn stdbuf_freopen(path : *const c_char, mode : *const c_char, stream : *mut c_void) -> *mut c_void {
+		opened(unsafe { freopen(path, mode, stream) })
+	}
+
+	extern fn stdbuf_isatty(fd : c_int) -> c_int {
+		if faked(fd) { 1 } else { unsafe { isatty(fd) } }
+	}
+
+	extern fn stdbuf_tcgetattr(fd : c_int, termios : *mut u8) -> c_int {
+		if faked(fd) { fake_tcgetattr(termios) } else { unsafe { tcgetattr(fd, termios) } }
+	}
+
+	pub struct Interpose {
+		replacement : *const u8,
+		original : *const u8,
+	}
+
+	#[link_section = "__DATA,__interpose"]
+	#[no_mangle]
+	pub static STDBUF_INTERPOSE: [Interpose, ..7] = [
+		Interpose { replacement : stdbuf_isatty as *const u8, original : isatty as *const u8 },
+		Interpose { replacement : stdbuf_tcgetattr as *const u8, original : tcgetattr as *const u8 },
+		Interpose { replacement : stdbuf_write as *const u8, original : write as *const u8 },
+		Interpose { replacement : stdbuf_fwrite as *const u8, original : fwrite as *const u8 },
+		Interpose { replacement : stdbuf_fopen as *const u8, original : fopen as *const u8 },
+		Interpose { replacement : stdbuf_fdopen as *const u8, original : fdopen as *const u8 },
+		Interpose { replacement : stdbuf_freopen as *const u8, original : freopen as *const u8 },
+	];
+}
+
+// `output` for stdout and stderr, which something may have written to
+// before us, another library's constructor perhaps
+fn set_buffer(stream : *mut c_void, name : &str, value : &str, output : bool) {
+	let (mode, size) = match value {
+		"0" => (_IONBF, 0),
+		"L" => (_IOLBF, 0),
+		size => match from_str::<size_t>(size) {
+			Some(n) if n > 0 => (_IOFBF, n),
+			_ => {
+				report(format!("invalid buffering mode '{}' for {}", value, name).as_slice());
+				return;
+			}
+		},
+	};
+	// Given a null buffer, glibc ignores the size and later allocates one
+	// of its default size, so a buffer of the requested size is allocated
+	// here. It belongs to the stream from now on and is never freed, as
+	// with GNU's libstdbuf.
+	let buf = if mode == _IOFBF {
+		let buf = unsafe { ::libc::malloc(size) };
+		if buf.is_null() {
+			report(format!("could not allocate a buffer of {} bytes for {}", size, name).as_slice());
+			return;
+		}
+		buf
+	} else {
+		ptr::null_mut()
+	};
+	// Held locked so that no thread of the command writes in between; what
+	// was already written goes out first rather than into the new buffer.
+	let failed = unsafe {
+		flockfile(stream);
+		if output {
+			fflush(stream);
+		}
+		let failed = setvbuf(stream, buf, mode, size) != 0;
+		funlockfile(stream);
+		failed
+	};
+	if failed {
+		unsafe { ::libc::free(buf) };
+		report(format!("could not set buffering of {} to {}", name, value).as_slice());
+	}
+}
+
+// the mode for a stream on `fd` opened after startup, if any was given
+fn mode_for(fd : c_int) -> Option<String> {
+	if let Some(list) = env("_STDBUF_FD") {
+		for entry in list.as_slice().split(',') {
+			let mut parts = entry.splitn(1, '=');
+			if let (Some(n), Some(mode)) = (parts.next(), parts.next()) {
+				if from_str::<c_int>(n) == Some(fd) {
+					return Some(mode.to_string());
+				}
+			}
+		}
+	}
+	env("_STDBUF_ALL")
+}
+
+// a stream just opened by the command, before any I/O on it, which is as
+// late as setvbuf() may be called; freopen() resets it, so it comes here
+// again
+fn opened(stream : *mut c_void) -> *mut c_void {
+	if stream.is_null() || !selected() {
+		return stream;
+	}
+	let fd = unsafe { fileno(stream) };
+	if let Some(value) = mode_for(fd) {
+		set_buffer(stream, format!("fd {}", fd).as_slice(), value.as_slice(), false);
+	}
+	stream
+}
+
+// set once init() has run: a library both preloaded and linked in, or
+// mapped twice under different names, is only to apply the modes once
+static INITIALIZED: AtomicBool = INIT_ATOMIC_BOOL;
+
+extern fn init() {
+	if INITIALIZED.swap(true, Ordering::SeqCst) {
+		return;
+	}
+	// past the depth nothing is replaced either
+	if !count_generation() {
+		SELECTED.store(2, Ordering::SeqCst);
+		return;
+	}
+	// the error descriptor too is left for the program it is meant for
+	if !selected() {
+		return;
+	}
+	// The descriptor is ours alone: it is closed on exec, and the variable
+	// removed, so that nothing the command runs writes into it by mistake.
+	if let Some(fd) = env("_STDBUF_ERRFD").and_then(|fd| from_str::<c_int>(fd.as_slice())) {
+		if fd > 2 && unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } == 0 {
+			ERR_FD.store(fd as int, Ordering::SeqCst);
+		}
+		unsafe { unsetenv(b"_STDBUF_ERRFD\0".as_ptr() as *const c_char) };
+	}
+	// missing when the library was preloaded by hand rather than by stdbuf
+	if let Some(protocol) = env("_STDBUF_PROTO") {
+		if protocol.as_slice() != PROTOCOL {
+			report(format!("stdbuf speaks protocol {}, this library {}; buffering left unchanged", protocol, PROTOCOL).as_slice());
+			return;
+		}
+	}
+	if let Some(value) = env("_STDBUF_I") {
+		set_buffer(unsafe { stdin }, "stdin", value.as_slice(), false);
+	}
+	if let Some(value) = env("_STDBUF_O") {
+		set_buffer(unsafe { stdout }, "stdout", value.as_slice(), true);
+	}
+	if let Some(value) = env("_STDBUF_E") {
+		set_buffer(unsafe { stderr }, "stderr", value.as_slice(), true);
+	}
+}
+
+// run by the dynamic loader as soon as the library is mapped, like a C
+// __attribute__((constructor)). The modes are set from here rather than by
+// replacing any C library function, so on macOS the library works inserted
+// into a two-level namespace image as is, with no need for the
+// DYLD_FORCE_FLAT_NAMESPACE current dyld ignores; only the replacements
+// above, for --fake-isatty, --trace-io and streams opened later, are
+// interposed.
+#[cfg(target_os = "linux")]
+#[link_section = ".init_array"]
+#[no_mangle]
+pub static STDBUF_INIT: extern fn() = init;
+
+#[cfg(target_os = "macos")]
+#[link_section = "__DATA,__mod_init_func"]
+#[no_mangle]
+pub static STDBUF_INIT: extern fn() = init;
+
+// The test binary has the library linked in, constructor and replacements
+// included, so it is its own fixture program: started again with the
+// variables set and STDBUF_FIXTURE naming one of the ignored fixture_*
+// tests, that test sees what a program with the library preloaded would.
+// The modes are read back through glibc's stdio_ext functions.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use libc::{c_int, c_void, size_t};
+	use std::c_str::ToCStr;
+	use std::io::{Command, File, TempDir};
+	use std::os;
+	use std::thread::Thread;
+	use super::{stdin, stdout, stderr, PRELOAD};
+	use super::interpose::{fopen, fwrite};
+
+	extern {
+		fn fclose(stream : *mut c_void) -> c_int;
+		fn __fbufsize(stream : *mut c_void) -> size_t;
+		fn __flbf(stream : *mut c_void) -> c_int;
+	}
+
+	static THREADS: uint = 8;
+	static LINES: uint = 500;
+
+	fn fixture(name : &str) -> bool {
+		os::getenv("STDBUF_FIXTURE").map_or(false, |fixture| fixture.as_slice() == name)
+	}
+
+	// runs fixture_NAME in a process of its own, with `env` added
+	fn run_fixture(name : &str, env : &[(&str, String)]) {
+		let mut command = Command::new(os::self_exe_name().unwrap());
+		command.arg("--ignored").arg(format!("fixture_{}", name)).env("STDBUF_FIXTURE", name);
+		for &(key, ref value) in env.iter() {
+			command.env(key, value.as_slice());
+		}
+		let output = command.output().unwrap();
+		let report = String::from_utf8_lossy(output.output.as_slice()).into_owned();
+		assert!(output.status.success(), "fixture {} failed:\n{}", name, report);
+		// a fixture the filter did not find would pass as well
+		assert!(report.as_slice().contains("1 passed"), "fixture {} did not run:\n{}", name, report);
+	}
+
+	// the constructor has set the modes before main
+	#[test]
+	#[ignore]
+	fn fixture_modes() {
+		if !fixture("modes") {
+			return;
+		}
+		unsafe {
+			assert_eq!(__fbufsize(stdin), 8192);
+			assert!(__flbf(stdout) != 0);
+			assert_eq!(__fbufsize(stderr), 65536);
+		}
+	}
+
+	#[test]
+	fn modes_before_main() {
+		run_fixture("modes", &[
+			("_STDBUF_I", "8192".to_string()),
+			("_STDBUF_O", "L".to_string()),
+			("_STDBUF_E", "65536".to_string()),
+			("_STDBUF_PROTO", super::PROTOCOL.to_string()),
+		]);
+	}
+
+	// Threads started at once open streams of their own, which all get the
+	// mode, and write to a shared one, losing and mixing up nothing.
+	#[test]
+	#[ignore]
+	fn fixture_threads() {
+		if !fixture("threads") {
+			return;
+		}
+		let path = os::getenv("STDBUF_FIXTURE_FILE").unwrap();
+		let mode = "w".to_c_str();
+		let shared = fopen(path.to_c_str().as_ptr(), mode.as_ptr());
+		assert!(!shared.is_null());
+		let handle = shared as uint;
+		let threads : Vec<_> = range(0, THREADS).map(|t| {
+			let own_path = format!("{}.{}", path, t);
+			Thread::spawn(move || {
+				let mode = "w".to_c_str();
+				let own = fopen(own_path.to_c_str().as_ptr(), mode.as_ptr());
+				assert!(!own.is_null());
+				assert!(unsafe { __flbf(own) } != 0);
+				for line in range(0, LINES) {
+					let text = format!("thread {} line {}\n", t, line);
+					let written = fwrite(text.as_ptr() as *const c_void, 1, text.len() as size_t, handle as *mut c_void);
+					assert_eq!(written, text.len() as size_t);
+				}
+				unsafe { fclose(own) };
+			})
+		}).collect();
+		for thread in threads.into_iter() {
+			assert!(thread.join().is_ok());
+		}
+		assert!(unsafe { __flbf(shared) } != 0);
+		assert_eq!(unsafe { fclose(shared) }, 0);
+	}
+
+	#[test]
+	fn threads_at_startup() {
+		let dir = TempDir::new("libstdbuf").unwrap();
+		let path = dir.path().join("shared");
+		run_fixture("threads", &[
+			("_STDBUF_ALL", "L".to_string()),
+			("STDBUF_FIXTURE_FILE", path.display().to_string()),
+		]);
+		let text = File::open(&path).read_to_string().unwrap();
+		let lines : Vec<&str> = text.as_slice().lines().collect();
+		assert_eq!(lines.len(), THREADS * LINES);
+		for t in range(0, THREADS) {
+			let prefix = format!("thread {} ", t);
+			let own : Vec<&str> = lines.iter().map(|l| *l).filter(|l| l.starts_with(prefix.as_slice())).collect();
+			let expected : Vec<String> = range(0, LINES).map(|line| format!("thread {} line {}", t, line)).collect();
+			assert_eq!(own.len(), LINES);
+			for (got, want) in own.iter().zip(expected.iter()) {
+				assert_eq!(*got, want.as_slice());
+			}
+		}
+	}
+
+	// The last generation takes the library out of the preload variable
+	// before any child of its own can be started, even one forked at once
+	// while other threads are busy opening streams.
+	#[test]
+	#[ignore]
+	fn fixture_fork_exec() {
+		if !fixture("fork_exec") {
+			return;
+		}
+		let busy : Vec<_> = range(0u, 4).map(|_| Thread::spawn(move || {
+			let (path, mode) = ("/dev/null".to_c_str(), "w".to_c_str());
+			for _ in range(0u, 200) {
+				let stream = fopen(path.as_ptr(), mode.as_ptr());
+				if !stream.is_null() {
+					unsafe { fclose(stream) };
+				}
+			}
+		})).collect();
+		let script = format!("printf '%s|%s' \"$_STDBUF_DEPTH\" \"${}\"", PRELOAD);
+		for _ in range(0u, 20) {
+			let output = Command::new("sh").arg("-c").arg(script.as_slice()).output().unwrap();
+			assert_eq!(String::from_utf8_lossy(output.output.as_slice()).as_slice(), "0|/nonexistent/libother.so");
+		}
+		for thread in busy.into_iter() {
+			assert!(thread.join().is_ok());
+		}
+	}
+
+	#[test]
+	fn fork_exec_at_startup() {
+		// the loader warns about the missing libraries, and goes on
+		run_fixture("fork_exec", &[
+			("_STDBUF_DEPTH", "1".to_string()),
+			(PRELOAD, "/nonexistent/libstdbuf.so:/nonexistent/libother.so".to_string()),
+		]);
+	}
+}
@@ -0,0 +1,107 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// systemd only accepts sd_notify messages from the main PID of a unit, which
+// is stdbuf, unless the unit says NotifyAccess=all. The child is therefore
+// given a socket of our own and whatever arrives there is sent on to the
+// manager from stdbuf.
+
+use libc::{c_int, c_void, size_t, ssize_t};
+use std::os;
+use std::thread::Thread;
+
+static AF_UNIX: c_int = 1;
+static SOCK_DGRAM: c_int = 2;
+static SOCK_CLOEXEC: c_int = 0o2000000;
+
+#[repr(C)]
+struct SockaddrUn {
+	sun_family : u16,
+	sun_path : [u8, ..108],
+}
+
+extern {
+	fn socket(domain : c_int, kind : c_int, protocol : c_int) -> c_int;
+	fn bind(fd : c_int, addr : *const SockaddrUn, len : u32) -> c_int;
+	fn recv(fd : c_int, buf : *mut c_void, len : size_t, flags : c_int) -> ssize_t;
+	fn sendto(fd : c_int, buf : *const c_void, len : size_t, flags : c_int, addr : *const SockaddrUn, addrlen : u32) -> ssize_t;
+}
+
+// "@name" is a Linux abstract socket, anything else a filesystem path
+fn address(name : &str) -> Option<(SockaddrUn, u32)> {
+	let bytes = name.as_bytes();
+	if bytes.is_empty() || bytes.len() >= 108 {
+		return None;
+	}
+	let mut addr = SockaddrUn { sun_family : AF_UNIX as u16, sun_path : [0u8, ..108] };
+	for (i, &b) in bytes.iter().enumerate() {
+		addr.sun_path[i] = b;
+	}
+	if bytes[0] == b'@' {
+		addr.sun_path[0] = 0;
+	}
+	Some((addr, (2 + bytes.len()) as u32))
+}
+
+pub struct Notifier {
+	fd : c_int,
+	target : SockaddrUn,
+	target_len : u32,
+	// NOTIFY_SOCKET for the child
+	pub child_socket : String,
+}
+
+impl Notifier {
+	// None when stdbuf was not started by systemd with a notify socket
+	pub fn from_env() -> Result<Option<Notifier>, String> {
+		let name = match os::getenv("NOTIFY_SOCKET") {
+			Some(name) => name,
+			None => return Ok(None),
+		};
+		let (target, target_len) = match address(name.as_slice()) {
+			Some(addr) => addr,
+			None => return Err(format!("invalid NOTIFY_SOCKET '{}'", name)),
+		};
+		let child_socket = format!("@stdbuf-notify-{}", unsafe { ::libc::getpid() });
+		let (proxy, proxy_len) = address(child_socket.as_slice()).unwrap();
+		let fd = unsafe { socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+		if fd < 0 || unsafe { bind(fd, &proxy, proxy_len) } < 0 {
+			return Err(format!("cannot create notification socket: {}", os::last_os_error()));
+		}
+		Ok(Some(Notifier { fd : fd, target : target, target_len : target_len, child_socket : child_socket }))
+	}
+
+	pub fn notify(&self, message : &str) {
+		send(self.fd, &self.target, self.target_len, message.as_bytes());
+	}
+
+	// relays the child's messages for as long as stdbuf runs
+	pub fn forward(&self) {
+		let fd = self.fd;
+		let target = SockaddrUn { sun_family : self.target.sun_family, sun_path : self.target.sun_path };
+		let target_len = self.target_len;
+		Thread::spawn(move || {
+			let mut buf = [0u8, ..4096];
+			loop {
+				let n = unsafe { recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, 0) };
+				if n < 0 {
+					if os::errno() == ::libc::EINTR as uint {
+						continue;
+					}
+					break;
+				}
+				send(fd, &target, target_len, buf.slice_to(n as uint));
+			}
+		}).detach();
+	}
+}
+
+fn send(fd : c_int, target : &SockaddrUn, target_len : u32, data : &[u8]) {
+	unsafe { sendto(fd, data.as_ptr() as *const c_void, data.len() as size_t, 0, target, target_len) };
+}
@@ -0,0 +1,150 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use filter::LineFilter;
+use libc::{c_char, c_int, pid_t, size_t};
+use std::c_str::CString;
+use time;
+use clock;
+
+// ts(1)'s default, for a bare --timestamp
+pub static TIMESTAMP_FORMAT: &'static str = "%b %d %H:%M:%S";
+
+#[deriving(Show, Clone)]
+enum Part {
+	Text(String),
+	// RFC 3339 unless given a strftime format
+	Timestamp(Option<String>),
+	Stream,
+	Pid,
+	Host,
+	Label,
+}
+
+// "{ts} {stream} {pid} | ", or "{ts:%H:%M:%S}" for a time of one's own
+// format; "{{" and "}}" stand for literal braces
+#[deriving(Show, Clone)]
+pub struct Template {
+	parts : Vec<Part>,
+}
+
+impl Template {
+	pub fn parse(spec : &str) -> Result<Template, String> {
+		let mut parts = Vec::new();
+		let mut text = String::new();
+		let mut rest = spec;
+		while !rest.is_empty() {
+			if rest.starts_with("{{") || rest.starts_with("}}") {
+				text.push(rest.char_at(0));
+				rest = rest.slice_from(2);
+				continue;
+			}
+			if !rest.starts_with("{") {
+				text.push(rest.char_at(0));
+				rest = rest.slice_from(rest.char_range_at(0).next);
+				continue;
+			}
+			let end = match rest.find('}') {
+				Some(end) => end,
+				None => return Err(format!("unterminated variable in prefix '{}'", spec)),
+			};
+			let part = match rest.slice(1, end) {
+				"ts" => Part::Timestamp(None),
+				var if var.starts_with("ts:") => Part::Timestamp(Some(try!(check_format(var.slice_from(3))))),
+				"stream" => Part::Stream,
+				"pid" => Part::Pid,
+				"host" => Part::Host,
+				"label" => Part::Label,
+				name => return Err(format!("unknown prefix variable '{{{}}}', expected ts, stream, pid, host or label", name)),
+			};
+			if !text.is_empty() {
+				parts.push(Part::Text(text));
+				text = String::new();
+			}
+			parts.push(part);
+			rest = rest.slice_from(end + 1);
+		}
+		if !text.is_empty() {
+			parts.push(Part::Text(text));
+		}
+		Ok(Template { parts : parts })
+	}
+
+	// --timestamp: the time in `format`, then a space, ahead of whatever
+	// --prefix adds
+	pub fn timestamped(format : &str, rest : Option<Template>) -> Result<Template, String> {
+		let mut parts = vec![Part::Timestamp(Some(try!(check_format(format)))), Part::Text(" ".to_string())];
+		if let Some(rest) = rest {
+			parts.extend(rest.parts.into_iter());
+		}
+		Ok(Template { parts : parts })
+	}
+}
+
+fn check_format(format : &str) -> Result<String, String> {
+	match time::strftime(format, &time::now()) {
+		Ok(_) => Ok(format.to_string()),
+		Err(e) => Err(format!("invalid timestamp format '{}': {}", format, e)),
+	}
+}
+
+extern {
+	fn gethostname(name : *mut c_char, len : size_t) -> c_int;
+}
+
+fn hostname() -> String {
+	let mut buf = [0 as c_char, ..256];
+	unsafe {
+		if gethostname(buf.as_mut_ptr(), buf.len() as size_t) != 0 {
+			return "localhost".to_string();
+		}
+		buf[buf.len() - 1] = 0;
+		String::from_utf8_lossy(CString::new(buf.as_ptr(), false).as_bytes_no_nul()).into_owned()
+	}
+}
+
+pub struct Prefixer {
+	template : Template,
+	stream : &'static str,
+	pid : String,
+	host : String,
+	label : String,
+}
+
+impl Prefixer {
+	pub fn new(template : Template, stream : &'static str, pid : pid_t, label : Option<String>) -> Prefixer {
+		Prefixer {
+			template : template,
+			stream : stream,
+			pid : pid.to_string(),
+			host : hostname(),
+			label : label.unwrap_or(String::new()),
+		}
+	}
+}
+
+impl LineFilter for Prefixer {
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		let mut out = String::new();
+		for part in self.template.parts.iter() {
+			match *part {
+				Part::Text(ref text) => out.push_str(text.as_slice()),
+				Part::Timestamp(None) => out.push_str(format!("{}", time::at(clock::now()).rfc3339()).as_slice()),
+				Part::Timestamp(Some(ref format)) => out.push_str(time::strftime(format.as_slice(), &time::at(clock::now())).unwrap_or(String::new()).as_slice()),
+				Part::Stream => out.push_str(self.stream),
+				Part::Pid => out.push_str(self.pid.as_slice()),
+				Part::Host => out.push_str(self.host.as_slice()),
+				Part::Label => out.push_str(self.label.as_slice()),
+			}
+		}
+		let mut prefixed = out.into_bytes();
+		prefixed.push_all(line.as_slice());
+		vec![prefixed]
+	}
+}
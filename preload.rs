@@ -0,0 +1,261 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// The buffering modes are applied inside the command by libstdbuf, which
+// the dynamic loader is told to map before anything else. The modes are
+// passed in _STDBUF_I, _STDBUF_O and _STDBUF_E exactly as GNU stdbuf passes
+// them, so either library works with either launcher: ours is looked for
+// next to the stdbuf executable first, then GNU's where coreutils installs
+// it.
+//
+// Our own library also reads _STDBUF_PROTO, the version of this contract,
+// and writes what goes wrong to the descriptor in _STDBUF_ERRFD, which
+// stdbuf reads and reports when it supervises the command.
+
+use std::os;
+use std::io::{BufferedReader, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::io::pipe::PipeStream;
+use std::io::stdio;
+use std::thread::Thread;
+use libc::c_int;
+
+// matches PROTOCOL in libstdbuf.rs
+pub static PROTOCOL: &'static str = "1";
+
+#[cfg(target_os = "macos")]
+static LIBRARY: &'static str = "liblibstdbuf.dylib";
+#[cfg(target_os = "macos")]
+pub static VARIABLE: &'static str = "DYLD_INSERT_LIBRARIES";
+
+#[cfg(not(target_os = "macos"))]
+static LIBRARY: &'static str = "liblibstdbuf.so";
+#[cfg(not(target_os = "macos"))]
+pub static VARIABLE: &'static str = "LD_PRELOAD";
+
+#[cfg(unix)]
+static GNU_LIBRARIES: [&'static str, ..4] = [
+	"/usr/libexec/coreutils/libstdbuf.so",
+	"/usr/lib/coreutils/libstdbuf.so",
+	"/usr/local/libexec/coreutils/libstdbuf.so",
+	"/usr/lib64/coreutils/libstdbuf.so",
+];
+
+#[cfg(unix)]
+pub fn library() -> Result<Path, String> {
+	let mut candidates = Vec::new();
+	if let Some(dir) = os::self_exe_path() {
+		candidates.push(dir.join(LIBRARY));
+	}
+	candidates.extend(GNU_LIBRARIES.iter().map(|p| Path::new(*p)));
+	match candidates.into_iter().find(|path| path.is_file()) {
+		Some(path) => Ok(path),
+		None => Err(format!("libstdbuf not found next to stdbuf or in {}; the buffering modes cannot be applied without it", GNU_LIBRARIES.connect(", "))),
+	}
+}
+
+// GNU's libstdbuf only sets the buffering; --fake-isatty needs our own
+pub fn is_ours(library : &Path) -> bool {
+	library.filename() == Some(LIBRARY.as_bytes())
+}
+
+// a libstdbuf the caller preloads on their own picks the modes up just as
+// well
+pub fn already_preloaded() -> bool {
+	match os::getenv(VARIABLE) {
+		Some(existing) => existing.as_slice().split(':').any(|lib| lib.contains("libstdbuf")),
+		None => false,
+	}
+}
+
+// System Integrity Protection: dyld drops every DYLD_* variable when it
+// loads a binary the system marks restricted (anything under /bin, /sbin,
+// /usr or /System that came with macOS), so the modes never reach it. A
+// script run by a restricted interpreter such as /bin/sh loses them the
+// same way. Returns the binary responsible, if any. There is nothing to
+// fall back to from the outside; the caller can only say so.
+#[cfg(target_os = "macos")]
+pub fn stripped_by(command : &Path) -> Option<Path> {
+	if restricted(command) {
+		return Some(command.clone());
+	}
+	match interpreter(command) {
+		Some(ref interp) if restricted(interp) => Some(interp.clone()),
+		_ => None,
+	}
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn stripped_by(_command : &Path) -> Option<Path> {
+	None
+}
+
+#[cfg(target_os = "macos")]
+fn restricted(path : &Path) -> bool {
+	// SF_RESTRICTED from <sys/stat.h>
+	static SF_RESTRICTED: u64 = 0x00080000;
+	match path.stat() {
+		Ok(stat) => stat.unstable.flags & SF_RESTRICTED != 0,
+		Err(_) => false,
+	}
+}
+
+// the program named on a "#!" line
+#[cfg(unix)]
+fn interpreter(path : &Path) -> Option<Path> {
+	use std::io::{File, BufferedReader};
+	let file = match File::open(path) {
+		Ok(file) => file,
+		Err(_) => return None,
+	};
+	let line = match BufferedReader::new(file).read_line() {
+		Ok(line) => line,
+		Err(_) => return None,
+	};
+	if !line.as_slice().starts_with("#!") {
+		return None;
+	}
+	line.as_slice().slice_from(2).words().next().map(|p| Path::new(p))
+}
+
+// A statically linked executable (busybox, Go programs) never runs the
+// dynamic loader, so nothing preloaded reaches it: an ELF file without a
+// PT_INTERP program header. Scripts are judged by their interpreter, and
+// anything that is not ELF is assumed to load libraries as usual.
+#[cfg(unix)]
+pub fn statically_linked(command : &Path) -> bool {
+	match interpreter(command) {
+		Some(ref interp) => elf_without_interp(interp).unwrap_or(false),
+		None => elf_without_interp(command).unwrap_or(false),
+	}
+}
+
+#[cfg(windows)]
+pub fn statically_linked(_command : &Path) -> bool {
+	false
+}
+
+// None when the file is not ELF or cannot be read
+#[cfg(unix)]
+fn elf_without_interp(path : &Path) -> Option<bool> {
+	use std::io::{File, SeekSet};
+	// program header type of the loader's path
+	static PT_INTERP: u32 = 3;
+	let mut file = match File::open(path) {
+		Ok(file) => file,
+		Err(_) => return None,
+	};
+	let ident = match file.read_exact(16) {
+		Ok(ident) => ident,
+		Err(_) => return None,
+	};
+	if ident.slice_to(4) != b"\x7fELF" {
+		return None;
+	}
+	let wide = ident[4] == 2;
+	let little = ident[5] == 1;
+	let u16_at = |data : &[u8], at : uint| -> u64 {
+		if little { data[at] as u64 | (data[at + 1] as u64 << 8) } else { (data[at] as u64 << 8) | data[at + 1] as u64 }
+	};
+	let uint_at = |data : &[u8], at : uint, len : uint| -> u64 {
+		let mut value = 0u64;
+		for i in range(0, len) {
+			let byte = if little { data[at + len - 1 - i] } else { data[at + i] };
+			value = (value << 8) | byte as u64;
+		}
+		value
+	};
+	// the rest of the ELF header, 52 or 64 bytes in all
+	let header = match file.read_exact(if wide { 48 } else { 36 }) {
+		Ok(header) => header,
+		Err(_) => return None,
+	};
+	let (phoff, phentsize, phnum) = if wide {
+		(uint_at(header.as_slice(), 16, 8), u16_at(header.as_slice(), 38), u16_at(header.as_slice(), 40))
+	} else {
+		(uint_at(header.as_slice(), 12, 4), u16_at(header.as_slice(), 26), u16_at(header.as_slice(), 28))
+	};
+	for i in range(0, phnum) {
+		if file.seek((phoff + i * phentsize) as i64, SeekSet).is_err() {
+			return None;
+		}
+		let kind = match file.read_exact(4) {
+			Ok(kind) => uint_at(kind.as_slice(), 0, 4) as u32,
+			Err(_) => return None,
+		};
+		if kind == PT_INTERP {
+			return Some(false);
+		}
+	}
+	Some(true)
+}
+
+// there is no dynamic loader variable to inject a library through; the
+// pipe pump stands in for it
+#[cfg(windows)]
+pub fn library() -> Result<Path, String> {
+	Err("changing the buffering of COMMAND is not supported on Windows".to_string())
+}
+
+// The pipe libstdbuf reports into; the command gets the write end.
+pub struct ErrorChannel {
+	pub write : c_int,
+	read : c_int,
+}
+
+impl ErrorChannel {
+	pub fn open() -> IoResult<ErrorChannel> {
+		let mut fds = [0 as c_int, ..2];
+		if unsafe { ::libc::pipe(fds.as_mut_ptr()) } != 0 {
+			return Err(IoError::last_error());
+		}
+		Ok(ErrorChannel { read : fds[0], write : fds[1] })
+	}
+
+	// Once the command has been started: each line it sends is passed on to
+	// our stderr, under the command's name, until every copy of the write
+	// end is gone.
+	pub fn report(mut self, command : String) {
+		unsafe { ::libc::close(self.write) };
+		let read = self.read;
+		self.write = -1;
+		self.read = -1;
+		Thread::spawn(move || {
+			let pipe = match PipeStream::open(read) {
+				Ok(pipe) => pipe,
+				Err(_) => return,
+			};
+			let mut reader = BufferedReader::new(pipe);
+			let mut err = stdio::stderr_raw();
+			while let Ok(line) = reader.read_line() {
+				let _ = write!(&mut err, "stdbuf: {}: {}", command, line);
+			}
+		}).detach();
+	}
+}
+
+// for a command that could not be started
+impl Drop for ErrorChannel {
+	fn drop(&mut self) {
+		for &fd in [self.read, self.write].iter() {
+			if fd >= 0 {
+				unsafe { ::libc::close(fd) };
+			}
+		}
+	}
+}
+
+// The command may already preload libraries of its own; ours goes first so
+// its constructor has run by the time theirs do.
+pub fn value(library : &Path) -> String {
+	match os::getenv(VARIABLE) {
+		Some(ref existing) if !existing.is_empty() => format!("{}:{}", library.display(), existing),
+		_ => library.display().to_string(),
+	}
+}
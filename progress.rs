@@ -0,0 +1,133 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::cmp;
+use std::io::stdio;
+use std::io::pipe::PipeStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, Ordering};
+use std::time::Duration;
+use regex::Regex;
+use clock;
+use filter::LineBuffer;
+use ticker::{Tick, Ticker};
+
+struct Shared {
+	bytes : AtomicUint,
+	lines : AtomicUint,
+	done : AtomicBool,
+	out : Mutex<PipeStream>,
+	start : u64,
+}
+
+impl Shared {
+	// whoever reads the descriptor may go away; that must not affect the
+	// command, so write errors only stop the reports
+	fn report(&self) -> bool {
+		let elapsed = (clock::precise_time_ns() - self.start) as f64 / 1e9;
+		let report = format!("{{\"elapsed\":{:.3},\"stdout\":{{\"bytes\":{},\"lines\":{}}}}}",
+			elapsed, self.bytes.load(Ordering::SeqCst), self.lines.load(Ordering::SeqCst));
+		self.out.lock().write_line(report.as_slice()).is_ok()
+	}
+}
+
+struct Reporter {
+	shared : Arc<Shared>,
+}
+
+impl Tick for Reporter {
+	fn tick(&mut self) -> bool {
+		!self.shared.done.load(Ordering::SeqCst) && self.shared.report()
+	}
+}
+
+// Reports what has been relayed so far as one JSON object per line, once a
+// second and once more at the end. The reports come from the ticker's
+// thread so they keep going while the relay is blocked on a quiet child.
+pub struct Progress {
+	shared : Arc<Shared>,
+}
+
+impl Progress {
+	pub fn start(out : PipeStream, ticker : &mut Ticker) -> Progress {
+		let shared = Arc::new(Shared {
+			bytes : AtomicUint::new(0),
+			lines : AtomicUint::new(0),
+			done : AtomicBool::new(false),
+			out : Mutex::new(out),
+			start : clock::precise_time_ns(),
+		});
+		ticker.every(Duration::seconds(1), box Reporter { shared : shared.clone() });
+		Progress { shared : shared }
+	}
+
+	pub fn count(&self, data : &[u8]) {
+		self.shared.bytes.fetch_add(data.len(), Ordering::SeqCst);
+		let lines = data.iter().filter(|&&b| b == b'\n').count();
+		self.shared.lines.fetch_add(lines, Ordering::SeqCst);
+	}
+
+	// The final report is written from here rather than by waking the
+	// ticker; the reporter notices at its next tick and stops.
+	pub fn finish(self) {
+		self.shared.done.store(true, Ordering::SeqCst);
+		self.shared.report();
+	}
+}
+
+// A bar on stderr for a job whose output size is known in advance: the
+// relayed lines, or only those matching `pattern`, against `total`. It is
+// only redrawn when the shown percentage changes, so a chatty child costs
+// a few hundred writes to the terminal at most.
+pub struct ProgressBar {
+	total : uint,
+	pattern : Option<Regex>,
+	lines : LineBuffer,
+	seen : uint,
+	shown : Option<uint>,
+}
+
+static BAR_WIDTH: uint = 40;
+
+impl ProgressBar {
+	pub fn new(total : uint, pattern : Option<Regex>) -> ProgressBar {
+		ProgressBar { total : total, pattern : pattern, lines : LineBuffer::new(), seen : 0, shown : None }
+	}
+
+	pub fn count(&mut self, data : &[u8]) {
+		self.seen += match self.pattern {
+			Some(ref re) => self.lines.push(data).iter().filter(|line| re.is_match(String::from_utf8_lossy(line.as_slice()).as_slice())).count(),
+			None => data.iter().filter(|&&b| b == b'\n').count(),
+		};
+		self.draw(false);
+	}
+
+	pub fn finish(mut self) {
+		if let (Some(ref re), Some(line)) = (self.pattern.as_ref(), self.lines.finish()) {
+			if re.is_match(String::from_utf8_lossy(line.as_slice()).as_slice()) {
+				self.seen += 1;
+			}
+		}
+		self.draw(true);
+		let _ = stdio::stderr_raw().write(b"\n");
+	}
+
+	fn draw(&mut self, always : bool) {
+		// a job may produce more than it was expected to; the bar stays full
+		let done = cmp::min(self.seen, self.total);
+		let percent = if self.total == 0 { 100 } else { done * 100 / self.total };
+		if self.shown == Some(percent) && !always {
+			return;
+		}
+		self.shown = Some(percent);
+		let filled = if self.total == 0 { BAR_WIDTH } else { done * BAR_WIDTH / self.total };
+		let bar = format!("\r[{}{}] {:3}% {}/{}", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), percent, self.seen, self.total);
+		let _ = stdio::stderr_raw().write(bar.as_bytes());
+	}
+}
@@ -0,0 +1,348 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// --pty: COMMAND runs on the slave side of a pseudo-terminal, so isatty()
+// says yes and programs that only line-buffer on a terminal do so without
+// libstdbuf. The slave is switched to raw mode: no echo of what we forward
+// to it, no "\n" turned into "\r\n", no ^C turned into a signal, so the
+// output reaches the pipeline byte for byte as COMMAND wrote it. Window size
+// changes of our own terminal are passed on to the slave, which sends
+// COMMAND its SIGWINCH.
+//
+// For the same reason the slave does not take on our own terminal's
+// settings, and those are never changed: stdbuf is a relay, not a terminal
+// emulator, and leaves its own terminal cooked, with keys handled as
+// signals (see shutdown.rs). Whatever COMMAND does to its terminal, a
+// curses program going raw say, happens to the slave alone and goes with
+// it, so there is nothing to restore when stdbuf exits or is killed.
+//
+// The standard library cannot give the child a controlling terminal, so it
+// is started through `stdbuf __pty-exec COMMAND...`, which claims the slave
+// as one in its new session and then execs COMMAND in its place. COMMAND
+// leads that session and is its terminal's foreground process group, so
+// job control in it works as on a real terminal; its keys on our own
+// terminal reach stdbuf, which passes the signals on to that group (see
+// shutdown.rs). --no-foreground leaves it a session without a controlling
+// terminal, for programs that must not get SIGHUP or SIGTTOU from one.
+
+use libc::{c_int, c_void, pid_t};
+use std::io::{IoError, IoResult, OtherIoError, standard_error};
+use std::io::pipe::PipeStream;
+use std::io::stdio;
+use std::thread::{Thread, JoinGuard};
+use std::ptr;
+use std::sync::atomic::{AtomicInt, Ordering, INIT_ATOMIC_INT};
+
+#[cfg(unix)]
+pub static SUPPORTED: bool = true;
+#[cfg(windows)]
+pub static SUPPORTED: bool = false;
+
+#[cfg(not(target_os = "macos"))]
+static TIOCGWINSZ: u64 = 0x5413;
+#[cfg(not(target_os = "macos"))]
+static TIOCSWINSZ: u64 = 0x5414;
+#[cfg(not(target_os = "macos"))]
+static TIOCSCTTY: u64 = 0x540E;
+#[cfg(target_os = "macos")]
+static TIOCGWINSZ: u64 = 0x40087468;
+#[cfg(target_os = "macos")]
+static TIOCSWINSZ: u64 = 0x80087467;
+#[cfg(target_os = "macos")]
+static TIOCSCTTY: u64 = 0x20007461;
+
+static SIGWINCH: c_int = 28;
+static TCSANOW: c_int = 0;
+
+// where ICANON and c_cc[VEOF] sit in the opaque struct termios, whose flags
+// are 32 bits wide on Linux and 64 on macOS (little-endian both)
+#[cfg(not(target_os = "macos"))]
+static ICANON_BYTE: uint = 12;
+#[cfg(not(target_os = "macos"))]
+static ICANON_BIT: u8 = 0x02;
+#[cfg(not(target_os = "macos"))]
+static VEOF_BYTE: uint = 17 + 4;
+#[cfg(target_os = "macos")]
+static ICANON_BYTE: uint = 25;
+#[cfg(target_os = "macos")]
+static ICANON_BIT: u8 = 0x01;
+#[cfg(target_os = "macos")]
+static VEOF_BYTE: uint = 32 + 0;
+// ^D
+static EOF_CHAR: u8 = 4;
+
+// the master of the running COMMAND, for the SIGWINCH handler
+static MASTER: AtomicInt = INIT_ATOMIC_INT;
+
+#[repr(C)]
+struct WinSize {
+	rows : u16,
+	cols : u16,
+	xpixel : u16,
+	ypixel : u16,
+}
+
+#[cfg(target_os = "linux")]
+#[link(name = "util")]
+extern {
+	fn openpty(master : *mut c_int, slave : *mut c_int, name : *mut u8, termp : *const c_void, winp : *const WinSize) -> c_int;
+}
+
+#[cfg(target_os = "macos")]
+extern {
+	fn openpty(master : *mut c_int, slave : *mut c_int, name : *mut u8, termp : *const c_void, winp : *const WinSize) -> c_int;
+}
+
+#[cfg(unix)]
+extern {
+	fn ioctl(fd : c_int, request : u64, ...) -> c_int;
+	fn tcgetattr(fd : c_int, termios : *mut u8) -> c_int;
+	fn tcsetattr(fd : c_int, action : c_int, termios : *const u8) -> c_int;
+	fn cfmakeraw(termios : *mut u8);
+	fn signal(signum : c_int, handler : extern fn(c_int)) -> *const u8;
+	fn setsid() -> pid_t;
+	fn tcsetpgrp(fd : c_int, pgrp : pid_t) -> c_int;
+}
+
+pub struct Pty {
+	pub master : c_int,
+	pub slave : c_int,
+}
+
+// Which of COMMAND's streams are the terminal; the others are pipes of
+// ours. Tools often pick colors by whether stdout is a terminal yet log
+// machine-readable data when stderr is not.
+#[deriving(Show, Clone)]
+pub struct Streams {
+	pub stdin : bool,
+	pub stdout : bool,
+	pub stderr : bool,
+}
+
+impl Streams {
+	// all three less those given to --pipe, each a comma list of stdin,
+	// stdout and stderr
+	pub fn parse(pipes : &[String]) -> Result<Streams, String> {
+		let mut streams = Streams { stdin : true, stdout : true, stderr : true };
+		for list in pipes.iter() {
+			let piped = try!(Streams::named(list.as_slice(), "--pipe"));
+			streams.stdin = streams.stdin && !piped.stdin;
+			streams.stdout = streams.stdout && !piped.stdout;
+			streams.stderr = streams.stderr && !piped.stderr;
+		}
+		if !streams.stdin && !streams.stdout && !streams.stderr {
+			return Err("--pipe leaves no stream for --pty".to_string());
+		}
+		Ok(streams)
+	}
+
+	// a comma list of stdin, stdout and stderr given to `option`
+	pub fn named(list : &str, option : &str) -> Result<Streams, String> {
+		let mut streams = Streams { stdin : false, stdout : false, stderr : false };
+		for name in list.split(',').filter(|n| !n.is_empty()) {
+			match name {
+				"stdin" => streams.stdin = true,
+				"stdout" => streams.stdout = true,
+				"stderr" => streams.stderr = true,
+				_ => return Err(format!("unknown stream '{}' for {}, expected stdin, stdout or stderr", name, option)),
+			}
+		}
+		Ok(streams)
+	}
+
+	// the descriptors, as libstdbuf reads them from _STDBUF_TTY
+	pub fn fds(&self) -> String {
+		let mut fds = Vec::new();
+		for &(fd, on) in [("0", self.stdin), ("1", self.stdout), ("2", self.stderr)].iter() {
+			if on {
+				fds.push(fd);
+			}
+		}
+		fds.connect(",")
+	}
+}
+
+// our own terminal's size, from whichever standard stream is one
+#[cfg(unix)]
+fn window_size() -> Option<WinSize> {
+	let mut size = WinSize { rows : 0, cols : 0, xpixel : 0, ypixel : 0 };
+	for fd in range(0, 3) {
+		if unsafe { ioctl(fd, TIOCGWINSZ, &mut size as *mut WinSize) } == 0 {
+			return Some(size);
+		}
+	}
+	None
+}
+
+#[cfg(windows)]
+fn window_size() -> Option<WinSize> {
+	None
+}
+
+// (columns, rows) of our own terminal, for the COLUMNS/LINES hint given to
+// a child without one
+pub fn terminal_size() -> Option<(u16, u16)> {
+	window_size().map(|size| (size.cols, size.rows))
+}
+
+// signal handler context: ioctl only
+#[cfg(unix)]
+extern fn resize(_signum : c_int) {
+	let master = MASTER.load(Ordering::SeqCst);
+	if master > 0 {
+		if let Some(size) = window_size() {
+			unsafe { ioctl(master as c_int, TIOCSWINSZ, &size as *const WinSize) };
+		}
+	}
+}
+
+impl Pty {
+	#[cfg(windows)]
+	pub fn open() -> IoResult<Pty> {
+		Err(standard_error(OtherIoError))
+	}
+
+	#[cfg(unix)]
+	pub fn open() -> IoResult<Pty> {
+		let mut master = 0;
+		let mut slave = 0;
+		let size = window_size();
+		let winp = match size {
+			Some(ref size) => size as *const WinSize,
+			None => ptr::null(),
+		};
+		if unsafe { openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), winp) } != 0 {
+			return Err(IoError::last_error());
+		}
+		// struct termios is opaque here; no platform's is anywhere near
+		// this large
+		let mut termios = [0u8, ..256];
+		unsafe {
+			if tcgetattr(slave, termios.as_mut_ptr()) == 0 {
+				cfmakeraw(termios.as_mut_ptr());
+				tcsetattr(slave, TCSANOW, termios.as_ptr());
+			}
+		}
+		MASTER.store(master as int, Ordering::SeqCst);
+		unsafe { signal(SIGWINCH, resize) };
+		Ok(Pty { master : master, slave : slave })
+	}
+
+	// COMMAND's output; reading fails with EIO once every holder of the
+	// slave has closed it, which ends the output like EOF on a pipe
+	pub fn reader(&self) -> IoResult<PipeStream> {
+		PipeStream::open(self.master)
+	}
+
+	// where stdin and --send-on responses go in
+	pub fn writer(&self) -> IoResult<PipeStream> {
+		match unsafe { ::libc::dup(self.master) } {
+			-1 => Err(IoError::last_error()),
+			fd => PipeStream::open(fd),
+		}
+	}
+
+	// a descriptor of the master for end_input(), to be closed by it
+	pub fn control(&self) -> IoResult<c_int> {
+		match unsafe { ::libc::dup(self.master) } {
+			-1 => Err(IoError::last_error()),
+			fd => Ok(fd),
+		}
+	}
+
+	// after the child has been started: only it may hold the slave, or the
+	// output would never end
+	pub fn close_slave(&mut self) {
+		if self.slave > 0 {
+			unsafe { ::libc::close(self.slave) };
+			self.slave = -1;
+		}
+	}
+}
+
+impl Drop for Pty {
+	fn drop(&mut self) {
+		MASTER.store(0, Ordering::SeqCst);
+		self.close_slave();
+	}
+}
+
+// Our stdin has ended. In raw mode no byte means end of input, and closing
+// our side would end COMMAND's output too, so the terminal goes back to
+// canonical mode and COMMAND reads the VEOF written after it as EOF, as from
+// ^D at the start of a line. `control` comes from Pty::control.
+#[cfg(unix)]
+pub fn end_input(control : c_int) -> IoResult<()> {
+	let mut termios = [0u8, ..256];
+	let result = unsafe {
+		if tcgetattr(control, termios.as_mut_ptr()) != 0 {
+			Err(IoError::last_error())
+		} else {
+			termios[ICANON_BYTE] |= ICANON_BIT;
+			termios[VEOF_BYTE] = EOF_CHAR;
+			if tcsetattr(control, TCSANOW, termios.as_ptr()) != 0
+				|| ::libc::write(control, &EOF_CHAR as *const u8 as *const c_void, 1) != 1 {
+				Err(IoError::last_error())
+			} else {
+				Ok(())
+			}
+		}
+	};
+	unsafe { ::libc::close(control) };
+	result
+}
+
+#[cfg(windows)]
+pub fn end_input(_control : c_int) -> IoResult<()> {
+	Ok(())
+}
+
+// In the child, `stdbuf __pty-exec`: the slave, the only terminal among its
+// standard streams, becomes the controlling terminal of the session it now
+// leads, with the child in the foreground, unless `foreground` is false.
+#[cfg(unix)]
+pub fn claim_terminal(foreground : bool) -> IoResult<()> {
+	unsafe { setsid() };
+	if !foreground {
+		return Ok(());
+	}
+	for fd in range(0, 3) {
+		if unsafe { ::libc::isatty(fd) } == 1 {
+			if unsafe { ioctl(fd, TIOCSCTTY, 0 as c_int) } != 0 {
+				return Err(IoError::last_error());
+			}
+			// the session leader's group is its own, as setsid left it
+			if unsafe { tcsetpgrp(fd, ::libc::getpid()) } != 0 {
+				return Err(IoError::last_error());
+			}
+			return Ok(());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(windows)]
+pub fn claim_terminal(_foreground : bool) -> IoResult<()> {
+	Ok(())
+}
+
+// With stdout a pipe, what COMMAND writes to the terminal is its stderr.
+// Joined once COMMAND has exited, so nothing it wrote last is lost.
+pub fn copy_to_stderr(mut reader : PipeStream) -> JoinGuard<()> {
+	Thread::spawn(move || {
+		let mut out = stdio::stderr_raw();
+		let mut buf = [0u8, ..4096];
+		// EIO once COMMAND has closed its side
+		while let Ok(n) = reader.read(&mut buf) {
+			if out.write(buf.slice_to(n)).is_err() {
+				break;
+			}
+		}
+	})
+}
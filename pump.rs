@@ -0,0 +1,245 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Where there is no dynamic loader to inject libstdbuf through (Windows,
+// statically linked executables), COMMAND's stdout is a pipe of ours and
+// the requested mode is applied here, to what we pass on, instead of
+// inside COMMAND. Its own C runtime still fully buffers the pipe, so this
+// decides how the output is handed on, not how soon COMMAND lets go of it.
+// stdin and stderr stay COMMAND's own and keep their defaults, unless
+// stdin is coalesced (-i L, --coalesce-stdin) by expect::forward_stdin.
+
+use std::io::IoResult;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use clock;
+use shutdown::Pending;
+use ticker::{Tick, Ticker};
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Mode {
+	Unbuffered,
+	Line,
+	// --flush-on: records ended by this byte instead of lines
+	Record(u8),
+	Size(uint),
+}
+
+// How much of `pending` may go out now: up to the last complete line or
+// record, or as many full blocks as there are.
+pub fn ready(mode : &Mode, pending : &[u8]) -> uint {
+	let delimiter = match *mode {
+		Mode::Unbuffered => return pending.len(),
+		Mode::Size(size) => return pending.len() - pending.len() % size,
+		Mode::Line => b'\n',
+		Mode::Record(byte) => byte,
+	};
+	match pending.iter().rposition(|&b| b == delimiter) {
+		Some(idx) => idx + 1,
+		None => 0,
+	}
+}
+
+// shared with --flush-interval, with Merged and with the panic hook
+struct Held {
+	// our stdout
+	out : Box<Writer + Send>,
+	pending : Vec<u8>,
+	// --merge: the start of a stdout line still to be completed; it is
+	// only passed on whole, so no stderr line can land in the middle
+	partial : Option<Vec<u8>>,
+}
+
+impl Held {
+	// also ends a last stdout line that never got its newline
+	fn write_out(&mut self) -> IoResult<()> {
+		let rest = mem::replace(&mut self.pending, Vec::new());
+		try!(self.out.write(rest.as_slice()));
+		let partial = match self.partial {
+			Some(ref mut partial) => mem::replace(partial, Vec::new()),
+			None => Vec::new(),
+		};
+		try!(self.out.write(partial.as_slice()));
+		self.out.flush()
+	}
+}
+
+// --merge: COMMAND's stderr, a line at a time, into the same stdout
+#[deriving(Clone)]
+pub struct Merged {
+	held : Arc<Mutex<Held>>,
+}
+
+impl Writer for Merged {
+	// whole stdout lines held back by the mode go out first, in order
+	fn write(&mut self, line : &[u8]) -> IoResult<()> {
+		let mut held = self.held.lock();
+		let pending = mem::replace(&mut held.pending, Vec::new());
+		try!(held.out.write(pending.as_slice()));
+		held.out.write(line)
+	}
+}
+
+struct FlushHeld {
+	held : Weak<Mutex<Held>>,
+}
+
+impl Tick for FlushHeld {
+	fn tick(&mut self) -> bool {
+		let strong = match self.held.upgrade() {
+			Some(strong) => strong,
+			None => return false,
+		};
+		let mut held = strong.lock();
+		if held.pending.is_empty() {
+			return true;
+		}
+		let rest = mem::replace(&mut held.pending, Vec::new());
+		// the relay finds out about a closed stdout on its next write
+		held.out.write(rest.as_slice()).is_ok()
+	}
+}
+
+struct HeldOnPanic {
+	held : Weak<Mutex<Held>>,
+}
+
+impl Pending for HeldOnPanic {
+	fn flush_pending(&self) {
+		if let Some(strong) = self.held.upgrade() {
+			if let Some(mut held) = strong.try_lock() {
+				let _ = held.write_out();
+			}
+		}
+	}
+}
+
+pub struct Pump {
+	held : Arc<Mutex<Held>>,
+	mode : Mode,
+	// --after: the mode to change to, and when
+	later : Option<(u64, Mode)>,
+}
+
+impl Pump {
+	pub fn new(out : Box<Writer + Send>, mode : Mode) -> Pump {
+		Pump { held : Arc::new(Mutex::new(Held { out : out, pending : Vec::new(), partial : None })), mode : mode, later : None }
+	}
+
+	// From now on stdout is passed on in whole lines only, and stderr lines
+	// may be written in between through what is returned.
+	pub fn merge(&mut self) -> Merged {
+		self.held.lock().partial = Some(Vec::new());
+		Merged { held : self.held.clone() }
+	}
+
+	// Whatever is held back goes out at least this often, full block or
+	// newline or not, so a slow COMMAND still shows progress. The relay
+	// is blocked in read() meanwhile, so the ticker does it; the job ends
+	// with the pump.
+	pub fn flush_every(&mut self, interval : Duration, ticker : &mut Ticker) {
+		ticker.every(interval, box FlushHeld { held : self.held.downgrade() });
+	}
+
+	// what a panic in stdbuf passes on, see shutdown::flush_on_panic
+	pub fn pending(&self) -> Box<Pending + Send> {
+		box HeldOnPanic { held : self.held.downgrade() }
+	}
+
+	// Quick feedback while COMMAND starts up, fewer and larger writes once it
+	// is past that. The change takes effect with the first write after
+	// `after`; whatever is held back then stays for the new mode.
+	pub fn switch_after(&mut self, after : Duration, mode : Mode) {
+		let at = clock::precise_time_ns() + after.num_milliseconds() as u64 * 1_000_000;
+		self.later = Some((at, mode));
+	}
+}
+
+impl Writer for Pump {
+	fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+		let due = match self.later {
+			Some((at, _)) => clock::precise_time_ns() >= at,
+			None => false,
+		};
+		if due {
+			self.mode = self.later.take().unwrap().1;
+		}
+		let mut held = self.held.lock();
+		let lines = match held.partial {
+			Some(ref mut partial) => {
+				partial.push_all(buf);
+				let end = match partial.iter().rposition(|&b| b == b'\n') {
+					Some(idx) => idx + 1,
+					None => return Ok(()),
+				};
+				let rest = partial.slice_from(end).to_vec();
+				let mut lines = mem::replace(partial, rest);
+				lines.truncate(end);
+				Some(lines)
+			},
+			None => None,
+		};
+		let buf = match lines {
+			Some(ref lines) => lines.as_slice(),
+			None => buf,
+		};
+		// what an earlier mode held back goes first, through ready() below
+		if self.mode == Mode::Unbuffered && held.pending.is_empty() {
+			return held.out.write(buf);
+		}
+		held.pending.push_all(buf);
+		let end = ready(&self.mode, held.pending.as_slice());
+		if end == 0 {
+			return Ok(());
+		}
+		let rest = held.pending.slice_from(end).to_vec();
+		let done = mem::replace(&mut held.pending, rest);
+		held.out.write(done.slice_to(end))
+	}
+
+	fn flush(&mut self) -> IoResult<()> {
+		self.held.lock().write_out()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::IoResult;
+	use std::sync::{Arc, Mutex};
+	use std::time::Duration;
+	use super::{Pump, Mode};
+
+	#[deriving(Clone)]
+	struct Captured {
+		data : Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Writer for Captured {
+		fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+			self.data.lock().push_all(buf);
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn switch_to_unbuffered_keeps_order() {
+		for mode in [Mode::Line, Mode::Size(8)].iter() {
+			let out = Captured { data : Arc::new(Mutex::new(Vec::new())) };
+			let mut pump = Pump::new(box out.clone(), mode.clone());
+			pump.write(b"held ").unwrap();
+			assert_eq!(*out.data.lock(), b"".to_vec());
+			pump.switch_after(Duration::zero(), Mode::Unbuffered);
+			pump.write(b"then").unwrap();
+			assert_eq!(*out.data.lock(), b"held then".to_vec());
+			pump.write(b"!").unwrap();
+			assert_eq!(*out.data.lock(), b"held then!".to_vec());
+		}
+	}
+}
@@ -0,0 +1,99 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use filter::LineFilter;
+use regex::Regex;
+
+static DEFAULT_REPLACEMENT: &'static str = "[REDACTED]";
+
+// Common credential shapes, enabled by --redact-secrets.
+static BUILTIN: [(&'static str, &'static str), ..6] = [
+	(r"AKIA[0-9A-Z]{16}", DEFAULT_REPLACEMENT),
+	(r"gh[pousr]_[A-Za-z0-9]{36,}", DEFAULT_REPLACEMENT),
+	(r"xox[abprs]-[A-Za-z0-9-]+", DEFAULT_REPLACEMENT),
+	(r"(?i)(bearer\s+)[A-Za-z0-9._~+/-]+=*", "${1}[REDACTED]"),
+	(r"-----BEGIN [A-Z ]*PRIVATE KEY-----", DEFAULT_REPLACEMENT),
+	(r"(?i)\b(password|passwd|secret|token|api[_-]?key)(\s*[=:]\s*)\S+", "${1}${2}[REDACTED]"),
+];
+
+#[deriving(Show, Clone)]
+pub struct RedactRule {
+	pub pattern : Regex,
+	pub replacement : String,
+}
+
+impl RedactRule {
+	// "REGEX[=REPLACEMENT]"; a literal '=' in REGEX is written "\="
+	pub fn parse(spec : &str) -> Result<RedactRule, String> {
+		let mut pattern = String::new();
+		let mut replacement = None;
+		let mut chars = spec.char_indices().peekable();
+		while let Some((idx, c)) = chars.next() {
+			match c {
+				'\\' if chars.peek().map(|&(_, n)| n) == Some('=') => {
+					chars.next();
+					pattern.push('=');
+				},
+				'=' => {
+					replacement = Some(spec.slice_from(idx + 1).to_string());
+					break;
+				},
+				c => pattern.push(c),
+			}
+		}
+		match Regex::new(pattern.as_slice()) {
+			Ok(re) => Ok(RedactRule {
+				pattern : re,
+				replacement : replacement.unwrap_or(DEFAULT_REPLACEMENT.to_string()),
+			}),
+			Err(e) => Err(format!("invalid --redact pattern '{}': {}", pattern, e)),
+		}
+	}
+
+	pub fn builtin() -> Vec<RedactRule> {
+		BUILTIN.iter().map(|&(re, replacement)| RedactRule {
+			pattern : Regex::new(re).unwrap(),
+			replacement : replacement.to_string(),
+		}).collect()
+	}
+}
+
+pub struct Redactor {
+	rules : Vec<RedactRule>,
+}
+
+impl Redactor {
+	pub fn new(rules : Vec<RedactRule>) -> Redactor {
+		Redactor { rules : rules }
+	}
+
+	// None when no rule matched
+	pub fn apply(&self, text : &str) -> Option<String> {
+		let mut text = text.to_string();
+		let mut changed = false;
+		for rule in self.rules.iter() {
+			if rule.pattern.is_match(text.as_slice()) {
+				text = rule.pattern.replace_all(text.as_slice(), rule.replacement.as_slice());
+				changed = true;
+			}
+		}
+		if changed { Some(text) } else { None }
+	}
+}
+
+impl LineFilter for Redactor {
+	// lines without a match are passed on byte for byte, only the ones that
+	// get rewritten go through a (lossy) UTF-8 conversion
+	fn push(&mut self, line : Vec<u8>) -> Vec<Vec<u8>> {
+		match self.apply(String::from_utf8_lossy(line.as_slice()).as_slice()) {
+			Some(text) => vec![text.into_bytes()],
+			None => vec![line],
+		}
+	}
+}
@@ -0,0 +1,227 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Captures in the zstd seekable format: one frame for every `lines` lines,
+// then the seek table that zstd's seekable decoder, and `stdbuf cat
+// --range`, use to go straight to the frames a range needs instead of
+// decompressing everything before it. Frames hold whole lines and all but
+// the last hold exactly `lines`, so the line count of the first frame is
+// all it takes to know which frame any line is in.
+
+use std::io::{File, Truncate, Write, SeekEnd, SeekSet, IoError, IoResult, OtherIoError};
+
+static SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+static SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+// number of frames, descriptor, magic
+static FOOTER_SIZE: i64 = 9;
+static CHECKSUM_FLAG: u8 = 0x80;
+
+#[cfg(feature = "zstd")]
+pub static SUPPORTED: bool = true;
+
+#[cfg(not(feature = "zstd"))]
+pub static SUPPORTED: bool = false;
+
+#[cfg(feature = "zstd")]
+mod sys {
+	use libc::{c_int, c_uint, c_void, size_t};
+
+	pub static LEVEL: c_int = 3;
+
+	#[link(name = "zstd")]
+	extern {
+		pub fn ZSTD_compressBound(src_size : size_t) -> size_t;
+		pub fn ZSTD_compress(dst : *mut c_void, dst_capacity : size_t, src : *const c_void, src_size : size_t, level : c_int) -> size_t;
+		pub fn ZSTD_decompress(dst : *mut c_void, dst_capacity : size_t, src : *const c_void, src_size : size_t) -> size_t;
+		pub fn ZSTD_isError(code : size_t) -> c_uint;
+	}
+}
+
+#[cfg(feature = "zstd")]
+fn compress(data : &[u8]) -> IoResult<Vec<u8>> {
+	use libc::{c_void, size_t};
+	unsafe {
+		let capacity = sys::ZSTD_compressBound(data.len() as size_t);
+		let mut frame = Vec::with_capacity(capacity as uint);
+		let n = sys::ZSTD_compress(frame.as_mut_ptr() as *mut c_void, capacity, data.as_ptr() as *const c_void, data.len() as size_t, sys::LEVEL);
+		if sys::ZSTD_isError(n) != 0 {
+			return Err(error("zstd compression failed"));
+		}
+		frame.set_len(n as uint);
+		Ok(frame)
+	}
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(frame : &[u8], size : uint) -> IoResult<Vec<u8>> {
+	use libc::{c_void, size_t};
+	unsafe {
+		let mut data = Vec::with_capacity(size);
+		let n = sys::ZSTD_decompress(data.as_mut_ptr() as *mut c_void, size as size_t, frame.as_ptr() as *const c_void, frame.len() as size_t);
+		if sys::ZSTD_isError(n) != 0 || n as uint != size {
+			return Err(error("corrupt zstd frame"));
+		}
+		data.set_len(size);
+		Ok(data)
+	}
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress(_data : &[u8]) -> IoResult<Vec<u8>> {
+	Err(error("zstd support is not compiled in"))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_frame : &[u8], _size : uint) -> IoResult<Vec<u8>> {
+	Err(error("zstd support is not compiled in"))
+}
+
+fn error(desc : &'static str) -> IoError {
+	IoError { kind : OtherIoError, desc : desc, detail : None }
+}
+
+pub struct SeekableFile {
+	file : File,
+	lines : uint,
+	frame : Vec<u8>,
+	frame_lines : uint,
+	// compressed and decompressed size of every frame written
+	entries : Vec<(u32, u32)>,
+	finished : bool,
+}
+
+impl SeekableFile {
+	// the file is started over: frames appended to an old capture would not
+	// be in its seek table
+	pub fn create(path : &Path, lines : uint) -> IoResult<SeekableFile> {
+		let file = try!(File::open_mode(path, Truncate, Write));
+		Ok(SeekableFile { file : file, lines : lines, frame : Vec::new(), frame_lines : 0, entries : Vec::new(), finished : false })
+	}
+
+	fn write_frame(&mut self) -> IoResult<()> {
+		let compressed = try!(compress(self.frame.as_slice()));
+		try!(self.file.write(compressed.as_slice()));
+		self.entries.push((compressed.len() as u32, self.frame.len() as u32));
+		self.frame.clear();
+		self.frame_lines = 0;
+		Ok(())
+	}
+}
+
+impl Writer for SeekableFile {
+	fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+		for &byte in buf.iter() {
+			self.frame.push(byte);
+			if byte == b'\n' {
+				self.frame_lines += 1;
+				if self.frame_lines == self.lines {
+					try!(self.write_frame());
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// the end of the capture: the last frame and the seek table go out
+	fn flush(&mut self) -> IoResult<()> {
+		if self.finished {
+			return Ok(());
+		}
+		self.finished = true;
+		if !self.frame.is_empty() {
+			try!(self.write_frame());
+		}
+		try!(self.file.write_le_u32(SKIPPABLE_MAGIC));
+		try!(self.file.write_le_u32((self.entries.len() * 8) as u32 + FOOTER_SIZE as u32));
+		for &(compressed, size) in self.entries.iter() {
+			try!(self.file.write_le_u32(compressed));
+			try!(self.file.write_le_u32(size));
+		}
+		try!(self.file.write_le_u32(self.entries.len() as u32));
+		try!(self.file.write_u8(0));
+		try!(self.file.write_le_u32(SEEKABLE_MAGIC));
+		self.file.flush()
+	}
+}
+
+// by the magic at the very end, so plain captures can be told apart
+pub fn is_seekable(path : &Path) -> bool {
+	let mut file = match File::open(path) {
+		Ok(file) => file,
+		Err(_) => return false,
+	};
+	file.seek(-4, SeekEnd).and_then(|_| file.read_le_u32()).ok() == Some(SEEKABLE_MAGIC)
+}
+
+// Lines `first` to `last` (from 1, inclusive; None for the end) of a
+// seekable capture, decompressing only the frames they are in.
+pub fn cat_range(path : &Path, first : uint, last : Option<uint>, out : &mut Writer) -> IoResult<()> {
+	let mut file = try!(File::open(path));
+	try!(file.seek(-FOOTER_SIZE, SeekEnd));
+	let frames = try!(file.read_le_u32()) as uint;
+	let descriptor = try!(file.read_u8());
+	if try!(file.read_le_u32()) != SEEKABLE_MAGIC {
+		return Err(error("not a seekable zstd capture"));
+	}
+	let entry_size = if descriptor & CHECKSUM_FLAG != 0 { 12 } else { 8 };
+	try!(file.seek(-(FOOTER_SIZE + (frames * entry_size) as i64), SeekEnd));
+	let mut entries = Vec::with_capacity(frames);
+	let mut offset = 0u64;
+	for _ in range(0, frames) {
+		let compressed = try!(file.read_le_u32());
+		let size = try!(file.read_le_u32());
+		if entry_size == 12 {
+			try!(file.read_le_u32());
+		}
+		entries.push((offset, compressed as uint, size as uint));
+		offset += compressed as u64;
+	}
+	if entries.is_empty() {
+		return Ok(());
+	}
+	let head = try!(read_frame(&mut file, entries[0]));
+	let per_frame = head.iter().filter(|&&b| b == b'\n').count();
+	let start = if entries.len() == 1 || per_frame == 0 { 0 } else { (first - 1) / per_frame };
+	let mut line = start * per_frame + 1;
+	for i in range(start, entries.len()) {
+		let data = if i == 0 { head.clone() } else { try!(read_frame(&mut file, entries[i])) };
+		for text in split_lines(data.as_slice()).into_iter() {
+			if last.map_or(false, |last| line > last) {
+				return Ok(());
+			}
+			if line >= first {
+				try!(out.write(text));
+			}
+			line += 1;
+		}
+	}
+	Ok(())
+}
+
+fn read_frame(file : &mut File, (offset, compressed, size) : (u64, uint, uint)) -> IoResult<Vec<u8>> {
+	try!(file.seek(offset as i64, SeekSet));
+	let frame = try!(file.read_exact(compressed));
+	decompress(frame.as_slice(), size)
+}
+
+// each line with its '\n', the last one possibly without
+fn split_lines(data : &[u8]) -> Vec<&[u8]> {
+	let mut lines = Vec::new();
+	let mut start = 0;
+	for (i, &byte) in data.iter().enumerate() {
+		if byte == b'\n' {
+			lines.push(data.slice(start, i + 1));
+			start = i + 1;
+		}
+	}
+	if start < data.len() {
+		lines.push(data.slice_from(start));
+	}
+	lines
+}
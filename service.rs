@@ -0,0 +1,162 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// --service: several commands run side by side under one stdbuf, as a
+// Procfile runner would. Every line they write is passed on whole, on our
+// stdout or stderr as they wrote it, behind the service's label: NAME.N,
+// N counting the services of the same NAME. When all have ended, each one's
+// status is reported to stderr.
+
+use std::io::{BufferedReader, Command, File, Append, Write};
+use std::io::pipe::PipeStream;
+use std::io::process::{CreatePipe, Ignored, ExitStatus, ExitSignal};
+use std::io::stdio::{mod, StdWriter};
+use std::io::FileNotFound;
+use std::sync::{Arc, Mutex};
+use std::thread::{Thread, JoinGuard};
+use init;
+use BufferType;
+
+#[deriving(Show)]
+pub struct Service {
+	pub name : String,
+	// the stdout and stderr modes of its own, overriding -o and -e
+	pub modes : Vec<(char, BufferType)>,
+	pub argv : Vec<String>,
+}
+
+impl Service {
+	// "web=python app.py", or with modes of its own "web:oL,e0=python app.py"
+	pub fn parse(spec : &str) -> Result<Service, String> {
+		let idx = match spec.find('=') {
+			Some(idx) => idx,
+			None => return Err(format!("invalid --service '{}', expected NAME=COMMAND", spec)),
+		};
+		let (name, modes) = match spec.slice_to(idx).find(':') {
+			Some(colon) => (spec.slice_to(colon), spec.slice(colon + 1, idx)),
+			None => (spec.slice_to(idx), ""),
+		};
+		if name.is_empty() {
+			return Err(format!("--service '{}' has no name", spec));
+		}
+		let mut parsed = Vec::new();
+		for mode in modes.split(',').filter(|m| !m.is_empty()) {
+			let stream = mode.char_at(0);
+			if stream != 'o' && stream != 'e' {
+				return Err(format!("invalid mode '{}' for service {}, expected oMODE or eMODE", mode, name));
+			}
+			match from_str::<BufferType>(mode.slice_from(1)) {
+				Some(value) => parsed.push((stream, value)),
+				None => return Err(format!("invalid mode '{}' for service {}", mode, name)),
+			}
+		}
+		let argv = try!(init::split_words(spec.slice_from(idx + 1)));
+		if argv.is_empty() {
+			return Err(format!("--service {} has no command", name));
+		}
+		Ok(Service { name : name.to_string(), modes : parsed, argv : argv })
+	}
+}
+
+// "web.1", "web.2", "worker.1", padded to a common width
+fn labels(services : &[Service]) -> Vec<String> {
+	let mut labels = Vec::new();
+	for (i, service) in services.iter().enumerate() {
+		let n = services.slice_to(i + 1).iter().filter(|s| s.name == service.name).count();
+		labels.push(format!("{}.{}", service.name, n));
+	}
+	let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+	labels.into_iter().map(|l| format!("{:<1$}", l, width)).collect()
+}
+
+// one line at a time, so the services' lines never run into each other
+fn copy_lines(from : PipeStream, label : String, out : Arc<Mutex<StdWriter>>, log : Option<Arc<Mutex<File>>>) -> JoinGuard<()> {
+	Thread::spawn(move || {
+		let mut reader = BufferedReader::new(from);
+		while let Ok(line) = reader.read_until(b'\n') {
+			let mut prefixed = format!("{} | ", label).into_bytes();
+			prefixed.push_all(line.as_slice());
+			let _ = out.lock().write(prefixed.as_slice());
+			if let Some(ref log) = log {
+				let _ = log.lock().write(line.as_slice());
+			}
+		}
+	})
+}
+
+// Runs every service to its end. `envs` holds each one's buffering
+// variables; with `log_dir`, each one's output is also appended to
+// DIR/NAME.N.log. Returns the first failing service's code, or 0.
+pub fn run(services : &[Service], envs : &[Vec<(String, String)>], log_dir : Option<&Path>) -> int {
+	let labels = labels(services);
+	let out = Arc::new(Mutex::new(stdio::stdout_raw()));
+	let err = Arc::new(Mutex::new(stdio::stderr_raw()));
+	let mut running = Vec::new();
+	for (i, service) in services.iter().enumerate() {
+		let label = labels[i].clone();
+		let log = match log_dir {
+			Some(dir) => {
+				let path = dir.join(format!("{}.log", label.as_slice().trim_right()));
+				match File::open_mode(&path, Append, Write) {
+					Ok(file) => Some(Arc::new(Mutex::new(file))),
+					Err(e) => {
+						let _ = writeln!(&mut *err.lock(), "stdbuf: {}: cannot open {}: {}", label, path.display(), e);
+						None
+					}
+				}
+			},
+			None => None,
+		};
+		let mut command = Command::new(service.argv[0].as_slice());
+		command.args(service.argv.slice_from(1));
+		for &(ref key, ref value) in envs[i].iter() {
+			command.env(key.as_slice(), value.as_slice());
+		}
+		// one stdin cannot be shared between them
+		command.stdin(Ignored).stdout(CreatePipe(false, true)).stderr(CreatePipe(false, true));
+		match command.spawn() {
+			Ok(mut process) => {
+				let readers = vec![
+					copy_lines(process.stdout.take().unwrap(), label.clone(), out.clone(), log.clone()),
+					copy_lines(process.stderr.take().unwrap(), label.clone(), err.clone(), log),
+				];
+				running.push((label, Some((process, readers)), (0, String::new())));
+			},
+			Err(e) => {
+				let _ = writeln!(&mut *err.lock(), "stdbuf: {}: exec {}: {}", label, service.argv[0], e);
+				let code = if e.kind == FileNotFound { 127 } else { 126 };
+				running.push((label, None, (code, "could not be started".to_string())));
+			}
+		}
+	}
+	let mut results = Vec::new();
+	for (label, started, failed) in running.into_iter() {
+		let (code, outcome) = match started {
+			Some((mut process, readers)) => {
+				let status = process.wait();
+				for reader in readers.into_iter() {
+					let _ = reader.join();
+				}
+				match status {
+					Ok(ExitStatus(0)) => (0, "exited successfully".to_string()),
+					Ok(ExitStatus(code)) => (code, format!("exited with status {}", code)),
+					Ok(ExitSignal(signum)) => (128 + signum, format!("was killed by signal {}", signum)),
+					Err(e) => (125, format!("could not be waited for: {}", e)),
+				}
+			},
+			None => failed,
+		};
+		results.push((label, code, outcome));
+	}
+	let mut err = err.lock();
+	for &(ref label, _, ref outcome) in results.iter() {
+		let _ = writeln!(&mut *err, "stdbuf: {}: {}", label.as_slice().trim_right(), outcome);
+	}
+	results.iter().map(|&(_, code, _)| code).find(|&code| code != 0).unwrap_or(0)
+}
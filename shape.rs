@@ -0,0 +1,57 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::cmp;
+use std::io::timer::sleep;
+use std::rand;
+use std::time::Duration;
+use time;
+
+// How --shape slows the relay down: every chunk waits `delay`, give or
+// take up to `jitter`, and overall no more than `rate` bytes a second go
+// out.
+#[deriving(Show, Clone)]
+pub struct Shape {
+	pub delay : Duration,
+	pub jitter : Duration,
+	pub rate : Option<u64>,
+}
+
+// Meant for testing what reads our output, so the waits are real even
+// under --fake-clock.
+pub struct Shaper {
+	shape : Shape,
+	sent : u64,
+	start_ns : u64,
+}
+
+impl Shaper {
+	pub fn new(shape : Shape) -> Shaper {
+		Shaper { shape : shape, sent : 0, start_ns : time::precise_time_ns() }
+	}
+
+	// called before a chunk of `len` bytes is relayed
+	pub fn pace(&mut self, len : uint) {
+		let jitter = self.shape.jitter.num_milliseconds();
+		let mut wait = self.shape.delay.num_milliseconds();
+		if jitter > 0 {
+			wait += (rand::random::<f64>() * (2 * jitter + 1) as f64) as i64 - jitter;
+		}
+		if let Some(rate) = self.shape.rate {
+			// when the chunk may go out without exceeding the rate
+			let due = (self.sent as f64 * 1e3 / rate as f64) as i64;
+			let elapsed = ((time::precise_time_ns() - self.start_ns) / 1_000_000) as i64;
+			wait = cmp::max(wait, due - elapsed);
+		}
+		if wait > 0 {
+			sleep(Duration::milliseconds(wait));
+		}
+		self.sent += len as u64;
+	}
+}
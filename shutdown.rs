@@ -0,0 +1,307 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// When stdbuf itself is told to terminate it must not just vanish: the
+// signal is passed on to the child, whose exit closes its output, so the
+// relay loop drains whatever is still buffered and waits for it as usual.
+// A panic in stdbuf terminates the child instead of orphaning it, once
+// whatever output and input stdbuf still holds back has been passed on.
+//
+// What the child gets can be fixed with --stop-signal, and --stop-timeout
+// escalates to SIGKILL if it is still around after that long, the way
+// container runtimes stop their entrypoint.
+//
+// --timeout stops the child the same way once it has run too long.
+//
+// Job control signals are passed on too: SIGTSTP stops the child and then
+// stdbuf itself, SIGCONT resumes the child. In a terminal the child stays in
+// our process group, so the shell's job control and keys like ^C and ^Z
+// reach it directly; SIGINT, SIGQUIT and SIGTSTP are then taken to be those
+// keys and not sent a second time. Anywhere else it gets a group of its own
+// and every signal goes to that whole group, reaching whatever the command
+// started.
+
+use libc::{c_int, c_void, pid_t};
+use std::any::Any;
+use std::io::timer::sleep;
+use std::mem;
+use std::rt::unwind;
+use std::sync::{StaticMutex, MUTEX_INIT};
+use std::sync::atomic::{AtomicBool, AtomicInt, Ordering, INIT_ATOMIC_BOOL, INIT_ATOMIC_INT};
+use std::thread::Thread;
+use std::time::Duration;
+
+static CHILD: AtomicInt = INIT_ATOMIC_INT;
+// 1: the child leads its own process group
+static GROUP: AtomicInt = INIT_ATOMIC_INT;
+static RECEIVED: AtomicInt = INIT_ATOMIC_INT;
+// 0: pass on whatever stdbuf received
+static STOP_SIGNAL: AtomicInt = INIT_ATOMIC_INT;
+// write end of the pipe that wakes the SIGKILL watchdog
+static WAKE_FD: AtomicInt = INIT_ATOMIC_INT;
+// what on_panic passes on, see flush_on_panic
+static PENDING_LOCK: StaticMutex = MUTEX_INIT;
+static mut PENDING: *mut Vec<Box<Pending + Send>> = 0 as *mut Vec<Box<Pending + Send>>;
+
+static FORWARDED: [c_int, ..6] = [1 /* SIGHUP */, 2 /* SIGINT */, 3 /* SIGQUIT */, 15 /* SIGTERM */, SIGTSTP, SIGCONT];
+static SIGINT: c_int = 2;
+static SIGQUIT: c_int = 3;
+static SIGTERM: c_int = 15;
+static SIGKILL: c_int = 9;
+
+#[cfg(not(target_os = "macos"))]
+static SIGTSTP: c_int = 20;
+#[cfg(not(target_os = "macos"))]
+static SIGCONT: c_int = 18;
+#[cfg(not(target_os = "macos"))]
+static SIGSTOP: c_int = 19;
+#[cfg(not(target_os = "macos"))]
+static SIGUSR1: c_int = 10;
+#[cfg(not(target_os = "macos"))]
+static SIGUSR2: c_int = 12;
+#[cfg(target_os = "macos")]
+static SIGTSTP: c_int = 18;
+#[cfg(target_os = "macos")]
+static SIGCONT: c_int = 19;
+#[cfg(target_os = "macos")]
+static SIGSTOP: c_int = 17;
+#[cfg(target_os = "macos")]
+static SIGUSR1: c_int = 30;
+#[cfg(target_os = "macos")]
+static SIGUSR2: c_int = 31;
+
+static SIGNALS: [(&'static str, c_int), ..13] = [
+	("HUP", 1), ("INT", SIGINT), ("QUIT", SIGQUIT), ("KILL", SIGKILL), ("USR1", SIGUSR1), ("USR2", SIGUSR2),
+	("PIPE", 13), ("ALRM", 14), ("TERM", SIGTERM), ("CONT", SIGCONT), ("STOP", SIGSTOP), ("TSTP", SIGTSTP),
+	("WINCH", 28),
+];
+
+extern {
+	fn signal(signum : c_int, handler : extern fn(c_int)) -> *const u8;
+	fn getpgrp() -> pid_t;
+	fn tcgetpgrp(fd : c_int) -> pid_t;
+}
+
+// "TERM", "SIGTERM" or "15"
+pub fn parse_signal(name : &str) -> Option<int> {
+	if let Some(n) = from_str::<int>(name) {
+		return if n > 0 && n < 65 { Some(n) } else { None };
+	}
+	let name = if name.starts_with("SIG") { name.slice_from(3) } else { name };
+	SIGNALS.iter().find(|&&(n, _)| n == name).map(|&(_, signum)| signum as int)
+}
+
+fn stop_signal(received : c_int) -> c_int {
+	match STOP_SIGNAL.load(Ordering::SeqCst) {
+		0 => received,
+		n => n as c_int,
+	}
+}
+
+// Sharing our process group, the child got the same signal from the
+// terminal already.
+fn from_terminal(signum : c_int) -> bool {
+	GROUP.load(Ordering::SeqCst) == 0 && (signum == SIGINT || signum == SIGQUIT || signum == SIGTSTP)
+}
+
+// signal handler context: only atomics, kill and write
+extern fn forward(signum : c_int) {
+	if signum == SIGTSTP {
+		if !from_terminal(SIGTSTP) {
+			send(SIGTSTP);
+		}
+		// the handler replaced the default action, so stop the hard way
+		unsafe { ::libc::kill(::libc::getpid(), SIGSTOP) };
+		return;
+	}
+	if signum == SIGCONT {
+		send(SIGCONT);
+		return;
+	}
+	RECEIVED.store(signum as int, Ordering::SeqCst);
+	stop(signum);
+}
+
+fn send(signum : c_int) {
+	let pid = CHILD.load(Ordering::SeqCst);
+	if pid > 0 {
+		let target = if GROUP.load(Ordering::SeqCst) == 1 { -pid } else { pid };
+		unsafe { ::libc::kill(target as pid_t, signum) };
+	}
+}
+
+fn stop(signum : c_int) {
+	let sent = stop_signal(signum);
+	// a --stop-signal other than the key's own is still for us to send
+	if sent != signum || !from_terminal(signum) {
+		send(sent);
+	}
+	let fd = WAKE_FD.load(Ordering::SeqCst);
+	if fd > 0 {
+		let byte = 0u8;
+		unsafe { ::libc::write(fd as c_int, &byte as *const u8 as *const c_void, 1) };
+	}
+}
+
+// Something that holds data back on its way out of stdbuf: the relay's
+// held output, the sinks, stdin being coalesced for COMMAND.
+pub trait Pending : Send {
+	// Writes out what is held. It must not wait: the panicking thread may
+	// be the one holding it, and is then left alone.
+	fn flush_pending(&self);
+}
+
+pub fn flush_on_panic(pending : Box<Pending + Send>) {
+	let _guard = PENDING_LOCK.lock();
+	unsafe {
+		if PENDING.is_null() {
+			PENDING = mem::transmute(box Vec::<Box<Pending + Send>>::new());
+		}
+		(*PENDING).push(pending);
+	}
+}
+
+fn on_panic(_msg : &(Any + Send), _file : &'static str, _line : uint) {
+	// before the child is stopped, so nothing it already wrote is lost
+	// with us
+	if let Some(_guard) = PENDING_LOCK.try_lock() {
+		unsafe {
+			if !PENDING.is_null() {
+				for pending in (*PENDING).iter() {
+					pending.flush_pending();
+				}
+			}
+		}
+	}
+	send(stop_signal(SIGTERM));
+}
+
+pub fn install(stop_signal : Option<int>, stop_timeout : Option<Duration>) {
+	if let Some(signum) = stop_signal {
+		STOP_SIGNAL.store(signum, Ordering::SeqCst);
+	}
+	if let Some(timeout) = stop_timeout {
+		let mut fds = [0 as c_int, ..2];
+		if unsafe { ::libc::pipe(fds.as_mut_ptr()) } == 0 {
+			let read_fd = fds[0];
+			WAKE_FD.store(fds[1] as int, Ordering::SeqCst);
+			Thread::spawn(move || {
+				let mut byte = 0u8;
+				if unsafe { ::libc::read(read_fd, &mut byte as *mut u8 as *mut c_void, 1) } == 1 {
+					sleep(timeout);
+					// cleared once the child has been reaped, so a recycled
+					// PID is never hit
+					send(SIGKILL);
+				}
+			}).detach();
+		}
+	}
+	for &signum in FORWARDED.iter() {
+		unsafe { signal(signum, forward) };
+	}
+	unsafe { unwind::register(on_panic) };
+}
+
+// the signal to send for a termination request that arrived before the
+// child was known
+pub fn pending_stop_signal() -> Option<int> {
+	received().map(|signum| stop_signal(signum as c_int) as int)
+}
+
+// stops the child as if stdbuf had been told to terminate, --stop-timeout
+// included
+pub fn stop_child() {
+	stop(SIGTERM);
+}
+
+// Whether the terminal's job control already covers the child: we are in
+// its foreground process group, so the child can simply stay in ours.
+pub fn in_foreground() -> bool {
+	unsafe { ::libc::isatty(0) == 1 && tcgetpgrp(0) == getpgrp() }
+}
+
+// for a child started in a process group of its own
+pub fn set_child_group(own : bool) {
+	GROUP.store(if own { 1 } else { 0 }, Ordering::SeqCst);
+}
+
+// the running child, for reports
+pub fn child() -> Option<pid_t> {
+	match CHILD.load(Ordering::SeqCst) {
+		0 => None,
+		pid => Some(pid as pid_t),
+	}
+}
+
+pub fn set_child(pid : pid_t) {
+	CHILD.store(pid as int, Ordering::SeqCst);
+}
+
+static TIMED_OUT: AtomicBool = INIT_ATOMIC_BOOL;
+
+// counted from now, for all of `stdbuf run` rather than each command
+pub fn stop_after(timeout : Duration) {
+	Thread::spawn(move || {
+		sleep(timeout);
+		TIMED_OUT.store(true, Ordering::SeqCst);
+		stop(SIGTERM);
+	}).detach();
+}
+
+pub fn timed_out() -> bool {
+	TIMED_OUT.load(Ordering::SeqCst)
+}
+
+// the last terminating signal stdbuf received, if any
+pub fn received() -> Option<int> {
+	match RECEIVED.load(Ordering::SeqCst) {
+		0 => None,
+		n => Some(n),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse_signal, SIGNALS, SIGUSR1, SIGUSR2, SIGCONT, SIGSTOP, SIGTSTP};
+
+	#[test]
+	fn every_name_with_and_without_prefix() {
+		for &(name, signum) in SIGNALS.iter() {
+			assert_eq!(parse_signal(name), Some(signum as int));
+			assert_eq!(parse_signal(format!("SIG{}", name).as_slice()), Some(signum as int));
+		}
+	}
+
+	#[test]
+	fn platform_numbers() {
+		assert_eq!(parse_signal("USR1"), Some(SIGUSR1 as int));
+		assert_eq!(parse_signal("USR2"), Some(SIGUSR2 as int));
+		assert_eq!(parse_signal("CONT"), Some(SIGCONT as int));
+		assert_eq!(parse_signal("STOP"), Some(SIGSTOP as int));
+		assert_eq!(parse_signal("TSTP"), Some(SIGTSTP as int));
+		assert_eq!(parse_signal("TERM"), Some(15));
+		assert_eq!(parse_signal("KILL"), Some(9));
+	}
+
+	#[test]
+	fn numbers() {
+		assert_eq!(parse_signal("1"), Some(1));
+		assert_eq!(parse_signal("64"), Some(64));
+		assert_eq!(parse_signal("0"), None);
+		assert_eq!(parse_signal("65"), None);
+		assert_eq!(parse_signal("-15"), None);
+	}
+
+	#[test]
+	fn unknown_names() {
+		for name in ["", "SIG", "term", "sigterm", "SIGSIGTERM", "TERM ", "FOO"].iter() {
+			assert_eq!(parse_signal(*name), None);
+		}
+	}
+}
@@ -0,0 +1,323 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+use std::io::{File, Truncate, Write, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use libc;
+use regex::Regex;
+use time;
+use clock;
+use filter::LineBuffer;
+use shutdown::Pending;
+
+// Extra destinations for the command's output besides our own stdout.
+pub struct Sink {
+	name : String,
+	writer : Box<Writer + Send>,
+}
+
+pub struct Sinks {
+	// shared only with the panic hook
+	sinks : Arc<Mutex<Vec<Sink>>>,
+	window : Option<CaptureWindow>,
+}
+
+struct SinksOnPanic {
+	sinks : Weak<Mutex<Vec<Sink>>>,
+}
+
+impl Pending for SinksOnPanic {
+	fn flush_pending(&self) {
+		if let Some(strong) = self.sinks.upgrade() {
+			if let Some(mut sinks) = strong.try_lock() {
+				for sink in sinks.iter_mut() {
+					let _ = sink.writer.flush();
+				}
+			}
+		}
+	}
+}
+
+impl Sinks {
+	pub fn new() -> Sinks {
+		Sinks { sinks : Arc::new(Mutex::new(Vec::new())), window : None }
+	}
+
+	pub fn add(&mut self, name : String, writer : Box<Writer + Send>) {
+		self.sinks.lock().push(Sink { name : name, writer : writer });
+	}
+
+	// what a panic in stdbuf passes on, see shutdown::flush_on_panic; lines
+	// still waiting for a --capture-window to decide are not among it
+	pub fn pending(&self) -> Box<Pending + Send> {
+		box SinksOnPanic { sinks : self.sinks.downgrade() }
+	}
+
+	pub fn set_window(&mut self, window : CaptureWindow) {
+		self.window = Some(window);
+	}
+
+	pub fn write(&mut self, data : &[u8]) -> Vec<(String, IoError)> {
+		let lines = match self.window {
+			Some(ref mut window) => window.push(data),
+			None => return self.write_all(data),
+		};
+		let mut failed = Vec::new();
+		for line in lines.iter() {
+			failed.extend(self.write_all(line.as_slice()).into_iter());
+		}
+		failed
+	}
+
+	// A sink that fails once is dropped, so one full disk does not stop
+	// the relay; the failures are returned for reporting.
+	fn write_all(&mut self, data : &[u8]) -> Vec<(String, IoError)> {
+		let mut failed = Vec::new();
+		let mut sinks = self.sinks.lock();
+		let mut kept = Vec::with_capacity(sinks.len());
+		for mut sink in mem::replace(&mut *sinks, Vec::new()).into_iter() {
+			match sink.writer.write(data) {
+				Ok(()) => kept.push(sink),
+				Err(e) => failed.push((sink.name, e)),
+			}
+		}
+		*sinks = kept;
+		failed
+	}
+
+	// end of output: lets sinks that hold data back write it out
+	pub fn finish(&mut self) -> Vec<(String, IoError)> {
+		let rest = match self.window {
+			Some(ref mut window) => window.finish(),
+			None => Vec::new(),
+		};
+		let mut failed = Vec::new();
+		for line in rest.iter() {
+			failed.extend(self.write_all(line.as_slice()).into_iter());
+		}
+		for sink in self.sinks.lock().iter_mut() {
+			if let Err(e) = sink.writer.flush() {
+				failed.push((sink.name.clone(), e));
+			}
+		}
+		failed
+	}
+}
+
+// --tee with --tee-err: one file written from the relay and from the
+// thread passing stderr on, each write going in whole.
+#[deriving(Clone)]
+pub struct SharedFile {
+	file : Arc<Mutex<File>>,
+}
+
+impl SharedFile {
+	pub fn new(file : File) -> SharedFile {
+		SharedFile { file : Arc::new(Mutex::new(file)) }
+	}
+}
+
+impl Writer for SharedFile {
+	fn write(&mut self, data : &[u8]) -> IoResult<()> {
+		self.file.lock().write(data)
+	}
+}
+
+// A file capture cut into numbered parts FILE.001, FILE.002, ... of at most
+// `limit` bytes each. Parts end on line boundaries, so a line is only cut
+// when it is longer than a whole part; an unterminated line is held back
+// until its newline arrives or the capture is flushed.
+pub struct SplitFile {
+	base : Path,
+	limit : u64,
+	part : uint,
+	written : u64,
+	file : Option<File>,
+	partial : Vec<u8>,
+}
+
+impl SplitFile {
+	pub fn new(base : Path, limit : u64) -> SplitFile {
+		SplitFile { base : base, limit : limit, part : 0, written : 0, file : None, partial : Vec::new() }
+	}
+
+	fn put(&mut self, mut data : &[u8]) -> IoResult<()> {
+		while !data.is_empty() {
+			if self.file.is_none() || (self.written > 0 && self.written + data.len() as u64 > self.limit) {
+				self.part += 1;
+				self.file = Some(try!(File::open_mode(&part_name(&self.base, self.part), Truncate, Write)));
+				self.written = 0;
+			}
+			let room = (self.limit - self.written) as uint;
+			let take = if data.len() > room { room } else { data.len() };
+			try!(self.file.as_mut().unwrap().write(data.slice_to(take)));
+			self.written += take as u64;
+			data = data.slice_from(take);
+		}
+		Ok(())
+	}
+}
+
+impl Writer for SplitFile {
+	fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+		for &byte in buf.iter() {
+			self.partial.push(byte);
+			if byte == b'\n' {
+				let line = mem::replace(&mut self.partial, Vec::new());
+				try!(self.put(line.as_slice()));
+			}
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> IoResult<()> {
+		let rest = mem::replace(&mut self.partial, Vec::new());
+		self.put(rest.as_slice())
+	}
+}
+
+// FILE.001, FILE.002, ... for part 1, 2, ...
+pub fn part_name(base : &Path, part : uint) -> Path {
+	Path::new(format!("{}.{:03}", base.display(), part))
+}
+
+// Where a --capture-window opens or closes: at the first line matching a
+// pattern, or once the command has been running for a while.
+#[deriving(Show, Clone)]
+pub enum Bound {
+	Pattern(Regex),
+	After(Duration),
+}
+
+// Lets only part of the output through to the sinks: from the line where
+// `start` is reached up to and including the one where `end` is. Windows
+// between two patterns open again for every later section; once a time
+// offset has been involved in closing one, it stays closed.
+pub struct CaptureWindow {
+	start : Bound,
+	end : Option<Bound>,
+	open : bool,
+	done : bool,
+	started_ns : u64,
+	lines : LineBuffer,
+}
+
+impl CaptureWindow {
+	pub fn new(start : Bound, end : Option<Bound>) -> CaptureWindow {
+		CaptureWindow { start : start, end : end, open : false, done : false, started_ns : clock::precise_time_ns(), lines : LineBuffer::new() }
+	}
+
+	pub fn push(&mut self, data : &[u8]) -> Vec<Vec<u8>> {
+		let mut kept = Vec::new();
+		for line in self.lines.push(data).into_iter() {
+			if let Some(line) = self.pass(line) {
+				kept.push(line);
+			}
+		}
+		kept
+	}
+
+	pub fn finish(&mut self) -> Vec<Vec<u8>> {
+		match self.lines.finish() {
+			Some(line) => self.pass(line).into_iter().collect(),
+			None => Vec::new(),
+		}
+	}
+
+	fn pass(&mut self, line : Vec<u8>) -> Option<Vec<u8>> {
+		if self.done {
+			return None;
+		}
+		if !self.open {
+			if !self.reached(&self.start, line.as_slice()) {
+				return None;
+			}
+			self.open = true;
+		}
+		let closes = match self.end {
+			Some(ref end) => self.reached(end, line.as_slice()),
+			None => false,
+		};
+		if closes {
+			self.open = false;
+			self.done = match (&self.start, &self.end) {
+				(&Bound::Pattern(_), &Some(Bound::Pattern(_))) => false,
+				_ => true,
+			};
+		}
+		Some(line)
+	}
+
+	fn reached(&self, bound : &Bound, line : &[u8]) -> bool {
+		match *bound {
+			Bound::Pattern(ref re) => re.is_match(String::from_utf8_lossy(line).as_slice()),
+			Bound::After(offset) => {
+				let elapsed = clock::precise_time_ns() - self.started_ns;
+				elapsed >= offset.num_milliseconds() as u64 * 1_000_000
+			},
+		}
+	}
+}
+
+// Names a capture file after a template such as "logs/{cmd}-{date}-{pid}.log":
+// {cmd} is the command's base name, {date} the local time as
+// YYYYMMDD-HHMMSS, {pid} stdbuf's process ID and {seq} the lowest number
+// from 1 up that names a file not there yet, so repeated runs never clobber
+// each other's captures. "{{" and "}}" stand for literal braces.
+pub fn expand_name(template : &str, cmd : &str) -> Result<Path, String> {
+	let tm = time::at(clock::now());
+	let date = format!("{:04}{:02}{:02}-{:02}{:02}{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec);
+	let pid = unsafe { libc::getpid() }.to_string();
+	let mut seq = 1u;
+	loop {
+		let (name, uses_seq) = try!(expand(template, cmd, date.as_slice(), pid.as_slice(), seq));
+		let path = Path::new(name);
+		if !uses_seq || !path.exists() {
+			return Ok(path);
+		}
+		seq += 1;
+	}
+}
+
+fn expand(template : &str, cmd : &str, date : &str, pid : &str, seq : uint) -> Result<(String, bool), String> {
+	let mut name = String::new();
+	let mut uses_seq = false;
+	let mut rest = template;
+	while !rest.is_empty() {
+		if rest.starts_with("{{") || rest.starts_with("}}") {
+			name.push(rest.char_at(0));
+			rest = rest.slice_from(2);
+			continue;
+		}
+		if !rest.starts_with("{") {
+			name.push(rest.char_at(0));
+			rest = rest.slice_from(rest.char_range_at(0).next);
+			continue;
+		}
+		let end = match rest.find('}') {
+			Some(end) => end,
+			None => return Err(format!("unterminated variable in file name '{}'", template)),
+		};
+		match rest.slice(1, end) {
+			"cmd" => name.push_str(cmd),
+			"date" => name.push_str(date),
+			"pid" => name.push_str(pid),
+			"seq" => {
+				name.push_str(seq.to_string().as_slice());
+				uses_seq = true;
+			},
+			var => return Err(format!("unknown file name variable '{{{}}}', expected cmd, date, pid or seq", var)),
+		}
+		rest = rest.slice_from(end + 1);
+	}
+	Ok((name, uses_seq))
+}
@@ -0,0 +1,99 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// Sending stdbuf SIGUSR1 (or the --stats-signal) prints how far the relay
+// has got to stderr, without disturbing COMMAND or its output. The handler
+// only wakes a thread of ours; the report is written from there.
+// --heartbeat writes the same report on a timer instead.
+
+use libc::{c_int, c_void};
+use std::io::stdio;
+use std::sync::atomic::{AtomicInt, AtomicUint, Ordering, INIT_ATOMIC_INT, INIT_ATOMIC_UINT};
+use std::thread::Thread;
+use std::time::Duration;
+use clock;
+use shutdown;
+use ticker::{Tick, Ticker};
+
+static BYTES: AtomicUint = INIT_ATOMIC_UINT;
+static LINES: AtomicUint = INIT_ATOMIC_UINT;
+static RUNS: AtomicUint = INIT_ATOMIC_UINT;
+static TOTAL: AtomicUint = INIT_ATOMIC_UINT;
+// write end of the pipe that wakes the reporting thread
+static WAKE_FD: AtomicInt = INIT_ATOMIC_INT;
+
+extern {
+	fn signal(signum : c_int, handler : extern fn(c_int)) -> *const u8;
+}
+
+// for every chunk relayed
+pub fn count(data : &[u8]) {
+	BYTES.fetch_add(data.len(), Ordering::SeqCst);
+	LINES.fetch_add(data.iter().filter(|&&b| b == b'\n').count(), Ordering::SeqCst);
+}
+
+// for every COMMAND started, `stdbuf run` having several
+pub fn started() {
+	RUNS.fetch_add(1, Ordering::SeqCst);
+}
+
+// signal handler context: write only
+extern fn request(_signum : c_int) {
+	let fd = WAKE_FD.load(Ordering::SeqCst);
+	if fd > 0 {
+		let byte = 0u8;
+		unsafe { ::libc::write(fd as c_int, &byte as *const u8 as *const c_void, 1) };
+	}
+}
+
+fn report(started_ns : u64) {
+	let elapsed = (clock::precise_time_ns() - started_ns) as f64 / 1e9;
+	let child = match shutdown::child() {
+		Some(pid) => format!("running as {}", pid),
+		None => "not running".to_string(),
+	};
+	let mut err = stdio::stderr_raw();
+	let _ = writeln!(&mut err, "stdbuf: stats: {:.1}s elapsed, {} bytes and {} lines relayed, command {} of {} {}",
+		elapsed, BYTES.load(Ordering::SeqCst), LINES.load(Ordering::SeqCst),
+		RUNS.load(Ordering::SeqCst), TOTAL.load(Ordering::SeqCst), child);
+}
+
+struct Heartbeat {
+	started_ns : u64,
+}
+
+impl Tick for Heartbeat {
+	fn tick(&mut self) -> bool {
+		report(self.started_ns);
+		true
+	}
+}
+
+pub fn heartbeat(interval : Duration, runs : uint, ticker : &mut Ticker) {
+	TOTAL.store(runs, Ordering::SeqCst);
+	ticker.every(interval, box Heartbeat { started_ns : clock::precise_time_ns() });
+}
+
+pub fn install(signum : c_int, runs : uint) {
+	TOTAL.store(runs, Ordering::SeqCst);
+	let mut fds = [0 as c_int, ..2];
+	if unsafe { ::libc::pipe(fds.as_mut_ptr()) } != 0 {
+		return;
+	}
+	let read_fd = fds[0];
+	WAKE_FD.store(fds[1] as int, Ordering::SeqCst);
+	let started_ns = clock::precise_time_ns();
+	Thread::spawn(move || {
+		let mut byte = 0u8;
+		while unsafe { ::libc::read(read_fd, &mut byte as *mut u8 as *mut c_void, 1) } == 1 {
+			report(started_ns);
+		}
+	}).detach();
+	unsafe { signal(signum, request) };
+}
@@ -11,33 +11,191 @@
 
 extern crate getopts;
 extern crate libc;
-use getopts::{optopt, optflag, getopts, usage, Matches, OptGroup};
+extern crate time;
+extern crate regex;
+#[cfg(feature = "serialize")]
+extern crate serialize;
+use getopts::{optopt, optflag, optflagopt, optmulti, getopts, usage, Matches, OptGroup, HasArg};
 use std::os;
 use std::num;
-use std::io::Command;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::io::{BufferedReader, Command, File, Open, Append, Write, ReadWrite, SeekSet, IoError, IoResult, EndOfFile, FileNotFound, OtherIoError};
+use std::c_str::{CString, ToCStr};
+use std::ptr;
+use std::sync::{Arc, Mutex};
 use std::iter::range_inclusive;
 use std::num::Int;
+use std::io::pipe::PipeStream;
+use std::io::process::{StdioContainer, InheritFd, CreatePipe, Ignored, ProcessExit, ExitStatus, ExitSignal};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+use regex::Regex;
+use audit::AuditLog;
+use landlock::Ruleset;
+use caps::CapSet;
+use expect::{SendRule, Responder};
+use sink::{Sinks, SplitFile, SharedFile, CaptureWindow, Bound};
+use seekable::SeekableFile;
+use pump::Pump;
+use filter::{Pipeline, FilterKind, StripAnsi, Dedup, ExecFilter, Grouper, Truncator};
+use level::{Level, LevelRule, LevelFilter};
+use redact::{RedactRule, Redactor};
+use progress::{Progress, ProgressBar};
+use ticker::Ticker;
+use prefix::{Template, Prefixer};
+use notify::Notifier;
+use shape::{Shape, Shaper};
+use trigger::{Trigger, StderrWatch};
+use service::Service;
+
+mod audit;
+mod clock;
+mod landlock;
+mod caps;
+mod expect;
+mod shutdown;
+mod sink;
+mod filter;
+mod level;
+mod redact;
+mod progress;
+mod prefix;
+mod notify;
+mod init;
+mod preload;
+mod shape;
+mod trigger;
+mod seekable;
+mod pump;
+mod tail;
+mod pty;
+mod stats;
+mod service;
+mod ticker;
 
 static NAME: &'static str = "stdbuf";
 static VERSION: &'static str = "1.0.0";
 
-#[deriving(Show)]
-enum BufferType {
+#[cfg(target_os = "macos")]
+static STATS_SIGNAL: int = 30;
+#[cfg(not(target_os = "macos"))]
+static STATS_SIGNAL: int = 10;
+
+// A MODE as -i/-o/-e take it and GNU's libstdbuf reads it from _STDBUF_*:
+// "0", "L" or a size such as "4K". Parsing and printing round-trip, so
+// configuration files can use the same spelling.
+#[deriving(Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", deriving(Encodable, Decodable))]
+pub enum BufferType {
 	Default,
 	Unbuffered,
 	Line,
 	Size(u64)
 }
 
+impl FromStr for BufferType {
+	fn from_str(mode : &str) -> Option<BufferType> {
+		match mode {
+			"0" => Some(BufferType::Unbuffered),
+			"L" => Some(BufferType::Line),
+			// "0K" or "0.5" no less than "0": nothing can be held in it
+			size => parse_size(size).ok().map(|n| if n == 0 { BufferType::Unbuffered } else { BufferType::Size(n) }),
+		}
+	}
+}
+
+// the sizes come out in bytes, as they are passed on
+impl fmt::Show for BufferType {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			BufferType::Default => write!(f, "default"),
+			BufferType::Unbuffered => write!(f, "0"),
+			BufferType::Line => write!(f, "L"),
+			BufferType::Size(n) => write!(f, "{}", n),
+		}
+	}
+}
+
 #[deriving(Show)]
 struct ProgramOptions {
 	stdin : BufferType,
 	stdout : BufferType,
 	stderr : BufferType,
+	audit_log : Option<Path>,
+	landlock : Option<Ruleset>,
+	drop_caps : Option<CapSet>,
+	oom_score_adj : Option<int>,
+	send_on : Vec<SendRule>,
+	tee_fd : Option<libc::c_int>,
+	validate : bool,
+	verbose : bool,
+	stdout_file : Option<Path>,
+	stdout_template : Option<String>,
+	tee : Option<Path>,
+	tee_err : bool,
+	merge : bool,
+	only_for : Option<String>,
+	depth : Option<uint>,
+	// --env-only: the _STDBUF_* variables but no preload of our own
+	env_only : bool,
+	watch : bool,
+	heartbeat : Option<Duration>,
+	timeout : Option<Duration>,
+	// zstd-seekable capture, a frame for every so many lines
+	frame_lines : Option<uint>,
+	dedup : bool,
+	dedup_window : Option<uint>,
+	min_level : Option<Level>,
+	level_rules : Vec<LevelRule>,
+	group_by : Option<Regex>,
+	redact : Vec<RedactRule>,
+	progress_fd : Option<libc::c_int>,
+	split_output : Option<u64>,
+	prefix : Option<Template>,
+	label : Option<String>,
+	line_max : Option<uint>,
+	notify_systemd : bool,
+	notify_ready : Option<Regex>,
+	fake_clock : Option<(time::Timespec, f64)>,
+	stop_signal : Option<int>,
+	stop_timeout : Option<Duration>,
+	// None with --stats-signal none
+	stats_signal : Option<int>,
+	entrypoint : bool,
+	env_file : Option<Path>,
+	strip_ansi : bool,
+	filter_order : Option<Vec<FilterKind>>,
+	filter_exec : Option<String>,
+	raw : bool,
+	inherit_fds : bool,
+	capture_window : Option<(Bound, Option<Bound>)>,
+	after : Option<(Duration, BufferType)>,
+	progress_total : Option<uint>,
+	progress_pattern : Option<Regex>,
+	shape : Option<Shape>,
+	on_stderr : Vec<Trigger>,
+	report_env : bool,
+	pty : Option<pty::Streams>,
+	pipe_given : bool,
+	// --hold-stdin: COMMAND's input stays open after ours ends
+	hold_stdin : bool,
+	no_foreground : bool,
+	term_size : bool,
+	fake_isatty : Option<pty::Streams>,
+	flush_interval : Option<Duration>,
+	flush_on : Option<u8>,
+	trace_io : Option<Path>,
+	fd_modes : Vec<(uint, BufferType)>,
+	all_streams : Option<BufferType>,
+	coalesce_stdin : bool,
+	services : Vec<Service>,
+	service_logs : Option<Path>,
 }
 
 enum ErrMsg {
-	Retry,
+	Invalid,
 	Fatal
 }
 
@@ -53,67 +211,180 @@ fn print_version() {
 
 fn print_usage(opts: &[OptGroup]) {
 	let brief = 
-		"Usage: stdbuf OPTION... COMMAND\nRun COMMAND, with modified buffering operations for its standard streams\nMandatory arguments to long options are mandatory for short options too.";
+		"Usage: stdbuf OPTION... COMMAND\n  or:  stdbuf OPTION... run --and CMD [--and CMD]... [--keep-going] [--status first-failure|worst]\n  or:  stdbuf cat [--range START:END] [--grep RE] FILE\n  or:  stdbuf tail [-f] [-n LINES] FILE\n  or:  stdbuf OPTION... --service NAME=COMMAND [--service NAME=COMMAND]... [--service-logs DIR]\n  or:  stdbuf --gnu-compat OPTION... COMMAND\nRun COMMAND, with modified buffering operations for its standard streams\nMandatory arguments to long options are mandatory for short options too.";
 	let explaination = 
-		"If MODE is 'L' the corresponding stream will be line buffered.\nThis option is invalid with standard input.\n\nIf MODE is '0' the corresponding stream will be unbuffered.\n\nOtherwise MODE is a number which may be followed by one of the following:\n\nKB 1000, K 1024, MB 1000*1000, M 1024*1024, and so on for G, T, P, E, Z, Y.\nIn this case the corresponding stream will be fully buffered with the buffer size set to MODE bytes.\n\nNOTE: If COMMAND adjusts the buffering of its standard streams ('tee' does for e.g.) then that will override corresponding settings changed by 'stdbuf'.\nAlso some filters (like 'dd' and 'cat' etc.) don't use streams for I/O, and are thus unaffected by 'stdbuf' settings.\n";
+		"If MODE is 'L' the corresponding stream will be line buffered.\nFor standard input, stdbuf then passes the input on to COMMAND a whole line at a time.\n\nIf MODE is '0' the corresponding stream will be unbuffered.\n\nOtherwise MODE is a number which may be followed by one of the following:\n\nKB 1000, K 1024, MB 1000*1000, M 1024*1024, and so on for G, T, P, E, Z, Y.\nKiB, MiB, ... are 1024-based like K, M, ...; lowercase is accepted, and b is 512.\nFractions (1.5M) and products (64x1K) are accepted too.\nIn this case the corresponding stream will be fully buffered with the buffer size set to MODE bytes.\n\nNOTE: If COMMAND adjusts the buffering of its standard streams ('tee' does for e.g.) then that will override corresponding settings changed by 'stdbuf'.\nAlso some filters (like 'dd' and 'cat' etc.) don't use streams for I/O, and are thus unaffected by 'stdbuf' settings.\n";
 	println!("{}\n{}", getopts::usage(brief, opts), explaination);
 }
 
-fn parse_size(size : &str) -> Option<u64> {
-	let ext = size.trim_left_chars(|c: char| c.is_digit(10));
-	let num = size.trim_right_chars(|c: char| c.is_alphabetic());
-	let mut recovered = num.to_string();
-	recovered.push_str(ext);
-	if recovered.as_slice() != size {
+// "4096", "4K", "1.5M", or a product of those as dd takes it: "64x1K".
+// Fractions of a byte are dropped. Sizes that do not fit in 64 bits are
+// refused rather than wrapped around.
+fn parse_size(size : &str) -> Result<u64, String> {
+	let too_large = || format!("size '{}' is too large", size);
+	let mut total = 1u64;
+	for factor in size.split('x') {
+		let value = match try!(parse_factor(factor).ok_or(format!("invalid size '{}'", size))) {
+			Some(value) => value,
+			None => return Err(too_large()),
+		};
+		total = match total.checked_mul(value) {
+			Some(total) => total,
+			None => return Err(too_large()),
+		};
+	}
+	Ok(total)
+}
+
+// One number with its suffix: None if it is not a size at all, Some(None)
+// if it is one too large for 64 bits.
+fn parse_factor(factor : &str) -> Option<Option<u64>> {
+	let split = factor.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(factor.len());
+	let (num, ext) = (factor.slice_to(split), factor.slice_from(split));
+	let (whole, fraction) = match num.find('.') {
+		Some(idx) => (num.slice_to(idx), num.slice_from(idx + 1)),
+		None => (num, ""),
+	};
+	if (whole.is_empty() && fraction.is_empty()) || fraction.contains_char('.') {
+		return None;
+	}
+	let whole : u64 = if whole.is_empty() { 0 } else { match from_str(whole) { Some(n) => n, None => return Some(None) } };
+	// as GNU: K 1024, KB 1000, KiB 1024, and so on up to Y, in either
+	// case; a lone "b" is a 512-byte block, as for dd
+	let (base, power) : (u64, uint) = if ext.is_empty() {
+		(1, 0)
+	} else if ext == "b" {
+		(512, 1)
+	} else {
+		let power = match "KMGTPEZY".find(ext.char_at(0).to_uppercase()) {
+			Some(idx) => idx + 1,
+			None => return None,
+		};
+		match ext.slice_from(1) {
+			"" | "iB" => (1024, power),
+			"B" | "b" => (1000, power),
+			_ => return None,
+		}
+	};
+	let mut multiplier = 1u64;
+	for _ in range(0, power) {
+		multiplier = match multiplier.checked_mul(base) {
+			Some(m) => m,
+			None => return Some(None),
+		};
+	}
+	let part = if fraction.is_empty() {
+		0
+	} else {
+		match from_str::<f64>(format!("0.{}", fraction).as_slice()) {
+			Some(part) => (part * multiplier as f64) as u64,
+			None => return None,
+		}
+	};
+	Some(whole.checked_mul(multiplier).and_then(|bytes| bytes.checked_add(part)))
+}
+
+// "500ms", "2s", "1.5m", "1h30m", "1d"; a plain number is seconds. Every
+// option that takes a DURATION goes through here, so they all read alike.
+pub fn parse_duration(value : &str) -> Option<Duration> {
+	if value.is_empty() {
 		return None;
 	}
-	let buf_size : u64 = match from_str(num) {
-		Some(m) => m,
-		None => return None,
-	};
-	let (power, base) : (uint, u64) = match ext {
-		"" => (0, 0),
-		"KB" => (1, 1024),
-		"K" => (1, 1000),
-		"MB" => (2, 1024),
-		"M" => (2, 1000),
-		"GB" => (3, 1024),
-		"G" => (3, 1000),
-		"TB" => (4, 1024),
-		"T" => (4, 1000),
-		"PB" => (5, 1024),
-		"P" => (5, 1000),
-		"EB" => (6, 1024),
-		"E" => (6, 1000),
-		"ZB" => (7, 1024),
-		"Z" => (7, 1000),
-		"YB" => (8, 1024),
-		"Y" => (8, 1000),
-		_ => return None,
-	};
-	Some(buf_size * base.pow(power))
-}
-
-fn check_option(matches : &Matches, name : &str, modified : &mut bool) -> Option<BufferType> {
+	let mut total = 0.0;
+	let mut rest = value;
+	while !rest.is_empty() {
+		let split = rest.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(rest.len());
+		let num = match from_str::<f64>(rest.slice_to(split)) {
+			Some(n) if n >= 0.0 => n,
+			_ => return None,
+		};
+		rest = rest.slice_from(split);
+		let unit_len = rest.find(|c: char| c.is_digit(10) || c == '.').unwrap_or(rest.len());
+		let ms_per_unit = match rest.slice_to(unit_len) {
+			"ms" => 1.0,
+			// only a lone number may leave the unit out
+			"" if split == value.len() => 1000.0,
+			"s" => 1000.0,
+			"m" => 60_000.0,
+			"h" => 3_600_000.0,
+			"d" => 86_400_000.0,
+			_ => return None,
+		};
+		total += num * ms_per_unit;
+		rest = rest.slice_from(unit_len);
+	}
+	Some(Duration::milliseconds(total as i64))
+}
+
+// One end of --capture-window: "+DURATION" after the start, or a regex.
+fn parse_bound(value : &str) -> Result<Bound, String> {
+	if value.starts_with("+") {
+		return match parse_duration(value.slice_from(1)) {
+			Some(offset) => Ok(Bound::After(offset)),
+			None => Err(format!("invalid offset '{}' in --capture-window", value)),
+		};
+	}
+	match Regex::new(value) {
+		Ok(re) => Ok(Bound::Pattern(re)),
+		Err(e) => Err(format!("invalid pattern '{}' in --capture-window: {}", value, e)),
+	}
+}
+
+// "delay=50ms,jitter=10ms,rate=1MBps", every part optional
+fn parse_shape(spec : &str) -> Result<Shape, String> {
+	let mut shape = Shape { delay : Duration::zero(), jitter : Duration::zero(), rate : None };
+	for part in spec.split(',').filter(|p| !p.is_empty()) {
+		let (key, value) = match part.find('=') {
+			Some(idx) => (part.slice_to(idx), part.slice_from(idx + 1)),
+			None => return Err(format!("invalid --shape setting '{}', expected KEY=VALUE", part)),
+		};
+		match key {
+			"delay" | "jitter" => {
+				let duration = match parse_duration(value) {
+					Some(d) => d,
+					None => return Err(format!("invalid duration '{}' for {} in --shape", value, key)),
+				};
+				if key == "delay" { shape.delay = duration } else { shape.jitter = duration }
+			},
+			"rate" => {
+				// "1MBps", "500Kps", "100Bps"
+				let size = if value.ends_with("ps") { value.slice_to(value.len() - 2) } else { value };
+				let bytes = parse_size(size).ok().or_else(|| {
+					if size.ends_with("B") { parse_size(size.slice_to(size.len() - 1)).ok() } else { None }
+				});
+				match bytes {
+					Some(n) if n > 0 => shape.rate = Some(n),
+					_ => return Err(format!("invalid rate '{}' in --shape, expected e.g. 1MBps", value)),
+				}
+			},
+			_ => return Err(format!("unknown --shape setting '{}', expected delay, jitter or rate", key)),
+		}
+	}
+	Ok(shape)
+}
+
+// MODE may carry a file to copy the stream into, as in "L:/var/log/out.log".
+fn check_option(matches : &Matches, name : &str, modified : &mut bool, sink : &mut Option<Path>) -> Option<BufferType> {
 	match matches.opt_str(name) {
-		Some(value) => {
+		Some(spec) => {
 			*modified = true;
-			match value.as_slice() {
-				"0" => Some(BufferType::Unbuffered),
-				"L" => {
-					if name == "input" {
-						println!("stdbuf: line buffering stdin is meaningless");
-						None
-					} else {
-						Some(BufferType::Line)
+			let value = match spec.as_slice().find(':') {
+				Some(idx) => {
+					if name != "output" {
+						println!("stdbuf: a file can only be attached to the output stream");
+						return None;
 					}
+					*sink = Some(Path::new(spec.as_slice().slice_from(idx + 1)));
+					spec.as_slice().slice_to(idx)
 				},
-				x => {
-					let size = match parse_size(x) {
-						Some(m) => m,
-						None => { println!("Invalid mode {}", x); return None }
-					};
-					Some(BufferType::Size(size))
+				None => spec.as_slice(),
+			};
+			match from_str::<BufferType>(value) {
+				Some(mode) => Some(mode),
+				// only a size gets this far, so this says what is wrong with it
+				None => {
+					println!("stdbuf: {}", parse_size(value).err().unwrap());
+					return None;
 				},
 			}
 		},
@@ -121,77 +392,2854 @@ fn check_option(matches : &Matches, name : &str, modified : &mut bool) -> Option
 	}
 }
 
-fn parse_options(args : &[String], options : &mut ProgramOptions, optgrps : &[OptGroup]) -> Result<OkMsg, ErrMsg> {
-	let matches = match getopts(args, optgrps) {
-		Ok(m) => m,
-		Err(_) => return Err(ErrMsg::Retry)
+// --oom-score-adj is for COMMAND only: stdbuf takes the score on for each
+// spawn, so the child inherits it, and goes back to its own right after,
+// before any filter or trigger command is started. The file stays open, as
+// --landlock may hide /proc later on. The first change has to happen before
+// --drop-caps takes away CAP_SYS_RESOURCE, which lowering the score needs;
+// the kernel then lets us go back down to it without.
+struct OomScore {
+	file : File,
+	own : int,
+	child : int,
+}
+
+impl OomScore {
+	fn apply(child : int) -> IoResult<OomScore> {
+		let mut file = try!(File::open_mode(&Path::new("/proc/self/oom_score_adj"), Open, ReadWrite));
+		let own = try!(file.read_to_string());
+		let own = match from_str::<int>(own.as_slice().trim()) {
+			Some(own) => own,
+			None => return Err(IoError { kind : OtherIoError, desc : "unreadable oom_score_adj", detail : Some(own) }),
+		};
+		let mut score = OomScore { file : file, own : own, child : child };
+		try!(score.for_child());
+		Ok(score)
+	}
+
+	fn for_child(&mut self) -> IoResult<()> {
+		let value = self.child;
+		self.set(value)
+	}
+
+	fn restore(&mut self) -> IoResult<()> {
+		let value = self.own;
+		self.set(value)
+	}
+
+	fn set(&mut self, value : int) -> IoResult<()> {
+		try!(self.file.seek(0, SeekSet));
+		self.file.write_str(value.to_string().as_slice())
+	}
+}
+
+// Looks the command up the same way execvp will.
+#[cfg(unix)]
+fn resolve_command(name : &str) -> Option<Path> {
+	if name.contains("/") {
+		let path = Path::new(name);
+		return if path.is_file() { Some(path) } else { None };
+	}
+	let search = match std::os::getenv("PATH") {
+		Some(p) => p,
+		None => "/usr/local/bin:/usr/bin:/bin".to_string(),
 	};
-	if matches.opt_present("help") {
-		return Ok(OkMsg::Help);
+	for dir in search.as_slice().split(':') {
+		let candidate = Path::new(if dir.is_empty() { "." } else { dir }).join(name);
+		if candidate.is_file() {
+			return Some(candidate);
+		}
 	}
-	if matches.opt_present("version") {
-		return Ok(OkMsg::Version);
+	None
+}
+
+// Looks the command up like cmd.exe: the current directory first, then
+// PATH, trying each PATHEXT extension when the name has none.
+#[cfg(windows)]
+fn resolve_command(name : &str) -> Option<Path> {
+	use std::ascii::AsciiExt;
+	let exts : Vec<String> = match std::os::getenv("PATHEXT") {
+		Some(list) => list.as_slice().split(';').filter(|e| !e.is_empty()).map(|e| e.to_ascii_lower()).collect(),
+		None => vec![".com".to_string(), ".exe".to_string(), ".bat".to_string(), ".cmd".to_string()],
+	};
+	let has_ext = Path::new(name).extension_str().is_some();
+	let lookup = |dir : &Path| -> Option<Path> {
+		let plain = dir.join(name);
+		if has_ext && plain.is_file() {
+			return Some(plain);
+		}
+		for ext in exts.iter() {
+			let candidate = dir.join(format!("{}{}", name, ext));
+			if candidate.is_file() {
+				return Some(candidate);
+			}
+		}
+		None
+	};
+	if name.contains("\\") || name.contains("/") {
+		return lookup(&Path::new("."));
 	}
-	let mut modified = false;
-	options.stdin = try!(check_option(&matches, "input", &mut modified).ok_or(ErrMsg::Fatal));
-	options.stdout = try!(check_option(&matches, "output", &mut modified).ok_or(ErrMsg::Fatal));
-	options.stderr = try!(check_option(&matches, "error", &mut modified).ok_or(ErrMsg::Fatal));
-	
-	if matches.free.len() != 1 {
-		return Err(ErrMsg::Retry);
+	let mut dirs = vec![Path::new(".")];
+	if let Some(search) = std::os::getenv("PATH") {
+		dirs.extend(search.as_slice().split(';').filter(|d| !d.is_empty()).map(|d| Path::new(d)));
 	}
-	if !modified {
-		println!("stdbuf: you must specify a buffering mode option");
-		return Err(ErrMsg::Fatal);
+	for dir in dirs.iter() {
+		if let Some(found) = lookup(dir) {
+			return Some(found);
+		}
 	}
-	Ok(OkMsg::Buffering)
+	None
 }
 
+#[cfg(unix)]
+fn command_for(name : &str) -> Command {
+	Command::new(name)
+}
 
-fn main() {
-	let args = os::args();
-	let optgrps = [
-		optopt("i", "input", "adjust standard input stream buffering", "MODE"),
-		optopt("o", "output", "adjust standard output stream buffering", "MODE"),
-		optopt("e", "error", "adjust standard error stream buffering", "MODE"),
-		optflag("", "help", "display this help and exit"),
-		optflag("", "version", "output version information and exit"),
+// Batch files and PowerShell scripts are not executables; they have to be
+// handed to their interpreter, as are cmd.exe builtins like `dir`.
+#[cfg(windows)]
+fn command_for(name : &str) -> Command {
+	use std::ascii::AsciiExt;
+	static BUILTINS: [&'static str, ..41] = [
+		"assoc", "break", "call", "cd", "chdir", "cls", "color", "copy", "date",
+		"del", "dir", "echo", "endlocal", "erase", "for", "ftype", "goto", "if",
+		"md", "mkdir", "mklink", "move", "path", "pause", "popd", "prompt",
+		"pushd", "rd", "rem", "ren", "rename", "rmdir", "set", "setlocal",
+		"shift", "start", "time", "title", "type", "ver", "vol",
 	];
-	let mut options = ProgramOptions{ stdin : BufferType::Default, stdout : BufferType::Default, stderr : BufferType::Default};
-	let mut command_idx = -1;
-	for i in range_inclusive(1, args.len()) {
-		match parse_options(args.slice(1, i), &mut options, &optgrps) {
-			Ok(OkMsg::Buffering) => {
-				command_idx = i-1;
-				println!("Program arg index = {}", command_idx);
-				break;
-			},
-			Ok(OkMsg::Help) => {
-				print_usage(&optgrps);
-				return;
-			},
-			Ok(OkMsg::Version) => {
-				print_version();
-				return;
+	let path = match resolve_command(name) {
+		Some(path) => path,
+		None => {
+			let lower = name.to_ascii_lower();
+			if BUILTINS.iter().any(|b| *b == lower.as_slice()) {
+				let mut command = Command::new("cmd.exe");
+				command.arg("/D").arg("/C").arg(name);
+				return command;
+			}
+			return Command::new(name);
+		}
+	};
+	let ext = path.extension_str().unwrap_or("").to_ascii_lower();
+	match ext.as_slice() {
+		"bat" | "cmd" => {
+			let mut command = Command::new("cmd.exe");
+			command.arg("/D").arg("/C").arg(&path);
+			command
+		},
+		"ps1" => {
+			let mut command = Command::new("powershell.exe");
+			command.arg("-NoProfile").arg("-ExecutionPolicy").arg("Bypass").arg("-File").arg(&path);
+			command
+		},
+		_ => Command::new(&path),
+	}
+}
+
+// Multi-call binaries like busybox share one inode between all applet
+// names, so the command only counts as ourselves when it is the same file
+// and is also invoked under our name.
+fn is_self(name : &str, argv0 : &str) -> bool {
+	let command = match resolve_command(name) {
+		Some(p) => p,
+		None => return false,
+	};
+	let invoked = command.filename_str().unwrap_or("");
+	if invoked != NAME && invoked != Path::new(argv0).filename_str().unwrap_or(NAME) {
+		return false;
+	}
+	let own = match std::os::self_exe_name() {
+		Some(p) => p,
+		None => return false,
+	};
+	match (command.stat(), own.stat()) {
+		(Ok(a), Ok(b)) => a.unstable.device == b.unstable.device && a.unstable.inode == b.unstable.inode,
+		_ => false,
+	}
+}
+
+// One pass, as POSIX has it: the first argument that is neither an option
+// nor an option's value is the command, as is the one after "--", and
+// whatever follows is the command's own. None when there is no command or
+// an option is not known.
+fn command_index(args : &[String], optgrps : &[OptGroup]) -> Option<uint> {
+	let mut i = 1;
+	while i < args.len() {
+		let arg = args[i].as_slice();
+		if arg == "--" {
+			return if i + 1 < args.len() { Some(i + 1) } else { None };
+		}
+		let takes_value = if arg.starts_with("--") {
+			let name = match arg.find('=') {
+				Some(idx) => arg.slice(2, idx),
+				None => arg.slice_from(2),
+			};
+			match optgrps.iter().find(|g| g.long_name.as_slice() == name) {
+				Some(g) => g.hasarg == HasArg::Yes && !arg.contains("="),
+				None => return None,
+			}
+		} else if arg.starts_with("-") && arg.len() > 1 {
+			match optgrps.iter().find(|g| g.short_name.as_slice() == arg.slice(1, 2)) {
+				Some(g) => g.hasarg == HasArg::Yes && arg.len() == 2,
+				None => return None,
+			}
+		} else {
+			return Some(i);
+		};
+		i += if takes_value { 2 } else { 1 };
+	}
+	None
+}
+
+// In "#!/usr/bin/stdbuf -oL -eL /usr/bin/python" the kernel passes
+// everything after the interpreter as a single argument; like env -S, it is
+// split back into words here. -S asks for it outright, which is needed when
+// the first option is --NAME=VALUE: that is taken as one option otherwise,
+// as its VALUE may hold spaces of its own. Returns None with nothing to split.
+fn split_shebang(args : &[String]) -> Result<Option<Vec<String>>, String> {
+	if args.len() < 2 {
+		return Ok(None);
+	}
+	let first = args[1].as_slice();
+	let (idx, line) = if first == "-S" || first == "--split-string" {
+		if args.len() < 3 {
+			return Err("-S needs the options and COMMAND to split".to_string());
+		}
+		(2, args[2].as_slice())
+	} else if first.starts_with("-S") {
+		(1, first.slice_from(2))
+	} else if first.starts_with("--split-string=") {
+		(1, first.slice_from("--split-string=".len()))
+	} else {
+		let joined = match first.find(|c : char| c.is_whitespace()) {
+			Some(space) => first.starts_with("-") && !(first.starts_with("--") && first.slice_to(space).contains("=")),
+			None => false,
+		};
+		if !joined {
+			return Ok(None);
+		}
+		(1, first)
+	};
+	let mut split = vec![args[0].clone()];
+	split.extend(try!(init::split_words(line)).into_iter());
+	split.push_all(args.slice_from(idx + 1));
+	Ok(Some(split))
+}
+
+// Explains why no command could be found, when there is something more
+// specific to say than "Invalid options".
+fn diagnose(args : &[String], optgrps : &[OptGroup]) -> Option<String> {
+	match getopts(args, optgrps) {
+		Err(f) => Some(f.to_string()),
+		Ok(m) => {
+			if m.free.is_empty() {
+				Some("missing command".to_string())
+			} else {
+				None
+			}
+		}
+	}
+}
+
+fn shell_quote(arg : &str) -> String {
+	let safe = !arg.is_empty() && arg.chars().all(|c| c.is_alphanumeric() || "_-./=:,+@%".contains_char(c));
+	if safe {
+		return arg.to_string();
+	}
+	format!("'{}'", arg.replace("'", "'\\''"))
+}
+
+// the first option given that only our own libstdbuf carries out
+fn own_library_option(options : &ProgramOptions) -> Option<&'static str> {
+	if options.fake_isatty.is_some() {
+		Some("--fake-isatty")
+	} else if options.trace_io.is_some() {
+		Some("--trace-io")
+	} else if !options.fd_modes.is_empty() {
+		Some("--fd")
+	} else if options.all_streams.is_some() {
+		Some("--all-streams")
+	} else if options.only_for.is_some() {
+		Some("--only-for")
+	} else if options.depth.is_some() {
+		Some("--depth")
+	} else {
+		None
+	}
+}
+
+// What the command's environment needs for libstdbuf to apply the modes;
+// nothing at all when every stream keeps its default. Windows has no
+// library to preload; the pipe pump applies the stdout mode instead.
+fn buffering_env(options : &ProgramOptions) -> Result<Vec<(String, String)>, String> {
+	let mut env = Vec::new();
+	for &(name, mode) in [("_STDBUF_I", &options.stdin), ("_STDBUF_O", &options.stdout), ("_STDBUF_E", &options.stderr)].iter() {
+		if let BufferType::Default = *mode {
+			continue;
+		}
+		// COMMAND reads from our pump then, not from its own buffer size
+		if name == "_STDBUF_I" && options.coalesce_stdin {
+			continue;
+		}
+		env.push((name.to_string(), mode.to_string()));
+	}
+	if let Some(ref streams) = options.fake_isatty {
+		env.push(("_STDBUF_TTY".to_string(), streams.fds()));
+	}
+	if let Some(ref file) = options.trace_io {
+		env.push(("_STDBUF_TRACE".to_string(), file.display().to_string()));
+	}
+	if !options.fd_modes.is_empty() {
+		let list : Vec<String> = options.fd_modes.iter().map(|&(fd, ref mode)| format!("{}={}", fd, mode)).collect();
+		env.push(("_STDBUF_FD".to_string(), list.connect(",")));
+	}
+	if let Some(ref mode) = options.all_streams {
+		env.push(("_STDBUF_ALL".to_string(), mode.to_string()));
+	}
+	// only worth passing on with something for them to hold back
+	if !env.is_empty() {
+		if let Some(ref glob) = options.only_for {
+			env.push(("_STDBUF_ONLY".to_string(), glob.clone()));
+		}
+		if let Some(depth) = options.depth {
+			env.push(("_STDBUF_DEPTH".to_string(), depth.to_string()));
+		}
+	}
+	if !env.is_empty() && !cfg!(windows) {
+		env.push(("_STDBUF_PROTO".to_string(), preload::PROTOCOL.to_string()));
+	}
+	// --env-only: one loaded system-wide, e.g. from /etc/ld.so.preload,
+	// would clash with a second copy
+	if !env.is_empty() && !cfg!(windows) && !options.env_only && !preload::already_preloaded() {
+		let library = try!(preload::library());
+		if let Some(option) = own_library_option(options) {
+			if !preload::is_ours(&library) {
+				return Err(format!("{} needs the libstdbuf built with this stdbuf, but only {} was found", option, library.display()));
+			}
+		}
+		env.push((preload::VARIABLE.to_string(), preload::value(&library)));
+	}
+	Ok(env)
+}
+
+// --term-size: COMMAND's output is a pipe, so tools that size their output
+// to the terminal fall back to 80 columns; COLUMNS and LINES, which most of
+// them consult first, carry the real size. Values the caller set already
+// are left alone, and there is nothing to say without a terminal.
+fn term_size_env() -> Vec<(String, String)> {
+	let mut env = Vec::new();
+	if let Some((cols, rows)) = pty::terminal_size() {
+		for &(name, value) in [("COLUMNS", cols), ("LINES", rows)].iter() {
+			if os::getenv(name).is_none() {
+				env.push((name.to_string(), value.to_string()));
+			}
+		}
+	}
+	env
+}
+
+// --report-env: COMMAND's environment as it was launched, for telling why
+// it failed here and not elsewhere. Values go through --redact and the
+// built-in secret patterns before they are written anywhere.
+fn record_env(audit : &mut AuditLog, overrides : &[(String, String)], options : &ProgramOptions) {
+	let mut env = os::env();
+	for &(ref key, ref value) in overrides.iter() {
+		env.retain(|&(ref k, _)| k != key);
+		env.push((key.clone(), value.clone()));
+	}
+	env.sort();
+	let mut rules = options.redact.clone();
+	rules.extend(RedactRule::builtin().into_iter());
+	let redactor = Redactor::new(rules);
+	let fields : Vec<(&str, String)> = env.iter().map(|&(ref key, ref value)| {
+		let line = format!("{}={}", key, value);
+		let redacted = redactor.apply(line.as_slice()).unwrap_or(line);
+		// a rule may have eaten the '=' along with the secret
+		let value = match redacted.as_slice().find('=') {
+			Some(idx) if redacted.as_slice().slice_to(idx) == key.as_slice() => redacted.as_slice().slice_from(idx + 1).to_string(),
+			_ => "[REDACTED]".to_string(),
+		};
+		(key.as_slice(), value)
+	}).collect();
+	audit.record("failure-env", fields.as_slice());
+}
+
+// Whether stdbuf has to stay between COMMAND and its output or signals.
+// If not, it simply execs COMMAND like GNU stdbuf: the terminal, isatty()
+// and the process ID are all COMMAND's own.
+fn needs_supervisor(options : &ProgramOptions) -> bool {
+	cfg!(windows)
+		|| filter::DEFAULT_ORDER.iter().any(|k| filter_configured(options, k))
+		|| options.line_max.is_some() || options.stdout_file.is_some() || options.tee_fd.is_some() || options.tee.is_some() || options.merge
+		|| options.progress_fd.is_some() || options.progress_total.is_some() || options.shape.is_some()
+		|| options.capture_window.is_some() || !options.send_on.is_empty() || options.notify_ready.is_some()
+		|| options.stop_signal.is_some() || options.stop_timeout.is_some() || options.entrypoint
+		|| options.audit_log.is_some() || !options.on_stderr.is_empty() || options.pty.is_some()
+		|| options.after.is_some() || options.flush_on.is_some() || options.coalesce_stdin
+		|| options.watch || options.heartbeat.is_some() || options.timeout.is_some() || options.flush_interval.is_some()
+}
+
+// Only returns if the exec failed.
+#[cfg(unix)]
+fn exec(command : &str, args : &[String], env : &[(String, String)]) -> IoError {
+	for &(ref key, ref value) in env.iter() {
+		os::setenv(key.as_slice(), value.as_slice());
+	}
+	let program = command.to_c_str();
+	let args : Vec<CString> = args.iter().map(|a| a.to_c_str()).collect();
+	let mut argv = vec![program.as_ptr()];
+	argv.extend(args.iter().map(|a| a.as_ptr()));
+	argv.push(ptr::null());
+	unsafe { libc::execvp(program.as_ptr(), argv.as_mut_ptr()) };
+	IoError::last_error()
+}
+
+#[cfg(windows)]
+fn exec(_command : &str, _args : &[String], _env : &[(String, String)]) -> IoError {
+	IoError { kind : OtherIoError, desc : "exec is not available on Windows", detail : None }
+}
+
+// stdbuf's own descriptors are never passed on, so without --inherit-fds
+// an exec'd COMMAND starts with the same three a spawned one gets.
+fn close_on_exec(fds : &[libc::c_int]) {
+	static F_SETFD: libc::c_int = 2;
+	static FD_CLOEXEC: libc::c_int = 1;
+	for &fd in fds.iter() {
+		unsafe { libc::fcntl(fd, F_SETFD, FD_CLOEXEC) };
+	}
+}
+
+// Enough to reproduce the run by hand: what is executed, with which
+// arguments, how, and what changes in its environment.
+fn print_plan(command : &str, args : &[String], env : &[(String, String)], supervised : bool, pumped : bool) {
+	let mut err = std::io::stdio::stderr();
+	let resolved = match resolve_command(command) {
+		Some(path) => path.display().to_string(),
+		None => format!("{} (not found in PATH)", command),
+	};
+	let mut argv = vec![shell_quote(command)];
+	argv.extend(args.iter().map(|a| shell_quote(a.as_slice())));
+	let _ = writeln!(&mut err, "stdbuf: executable: {}", resolved);
+	let _ = writeln!(&mut err, "stdbuf: argv: {}", argv.connect(" "));
+	if pumped {
+		let _ = writeln!(&mut err, "stdbuf: backend: pipe pump, stdout mode applied by stdbuf");
+	} else if supervised {
+		let _ = writeln!(&mut err, "stdbuf: backend: spawn with piped output");
+	} else {
+		let _ = writeln!(&mut err, "stdbuf: backend: exec");
+	}
+	if env.is_empty() {
+		let _ = writeln!(&mut err, "stdbuf: environment: unchanged");
+	}
+	for &(ref key, ref value) in env.iter() {
+		let _ = writeln!(&mut err, "stdbuf: environment: {}={}", key, shell_quote(value.as_slice()));
+	}
+}
+
+// The descriptor must already be open in our process, e.g. `3>>copy.log`.
+fn open_inherited_fd(fd : libc::c_int) -> Option<PipeStream> {
+	if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+		return None;
+	}
+	PipeStream::open(fd).ok()
+}
+
+// Descriptors above stderr that stdbuf was started with, before it opens
+// anything of its own.
+fn open_descriptors() -> Vec<libc::c_int> {
+	let mut fds = Vec::new();
+	// listing /dev/fd opens a descriptor for the listing itself, which is
+	// closed again by the time it is checked below
+	if let Ok(entries) = std::io::fs::readdir(&Path::new("/dev/fd")) {
+		for entry in entries.iter() {
+			if let Some(fd) = entry.filename_str().and_then(|name| from_str::<libc::c_int>(name)) {
+				if fd > 2 && unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0 {
+					fds.push(fd);
+				}
+			}
+		}
+	}
+	fds.sort();
+	fds
+}
+
+// Everything that can be checked without side effects on the system:
+// nothing is opened for writing and no restriction is applied.
+fn validate(options : &ProgramOptions, command : &str) -> Result<(), String> {
+	if resolve_command(command).is_none() {
+		return Err(format!("{}: command not found", command));
+	}
+	if let Some(fd) = options.tee_fd {
+		if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+			return Err(format!("descriptor {} given to --tee-fd is not open", fd));
+		}
+	}
+	if let Some(fd) = options.progress_fd {
+		if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+			return Err(format!("descriptor {} given to --progress-fd is not open", fd));
+		}
+	}
+	if let Some(ref path) = options.audit_log {
+		let dir = path.dir_path();
+		if !dir.is_dir() {
+			return Err(format!("cannot open audit log {}: directory does not exist", path.display()));
+		}
+	}
+	try!(buffering_env(options));
+	if options.landlock.is_some() && !landlock::SUPPORTED {
+		return Err("--landlock is not supported by this build".to_string());
+	}
+	if options.pty.is_some() && !pty::SUPPORTED {
+		return Err("--pty is not supported on this platform".to_string());
+	}
+	Ok(())
+}
+
+fn filter_configured(options : &ProgramOptions, kind : &FilterKind) -> bool {
+	match *kind {
+		FilterKind::StripAnsi => options.strip_ansi,
+		FilterKind::Redact => !options.redact.is_empty(),
+		FilterKind::Level => options.min_level.is_some(),
+		FilterKind::Dedup => options.dedup,
+		FilterKind::Exec => options.filter_exec.is_some(),
+		FilterKind::Prefix => options.prefix.is_some(),
+		FilterKind::Group => options.group_by.is_some(),
+	}
+}
+
+// Which stages stderr goes through as well: --redact, as no secret is to
+// reach the terminal or any copy of it either, and with --merge --prefix,
+// so every line of the one output says where it came from.
+fn stderr_stage(options : &ProgramOptions, kind : &FilterKind) -> bool {
+	match *kind {
+		FilterKind::Redact => true,
+		FilterKind::Prefix => options.merge,
+		_ => false,
+	}
+}
+
+// By default redaction runs early, so no later stage ever sees a secret;
+// --filters can reorder the stages. `stream` is "stdout" or "stderr".
+fn build_pipeline(options : &ProgramOptions, child : libc::pid_t, stream : &'static str) -> Result<Pipeline, String> {
+	let mut pipeline = Pipeline::new();
+	let order = match options.filter_order {
+		Some(ref order) => order.clone(),
+		None => filter::DEFAULT_ORDER.to_vec(),
+	};
+	for kind in order.iter().filter(|k| filter_configured(options, *k) && (stream == "stdout" || stderr_stage(options, *k))) {
+		match *kind {
+			FilterKind::StripAnsi => pipeline.add(box StripAnsi::new()),
+			FilterKind::Redact => pipeline.add(box Redactor::new(options.redact.clone())),
+			FilterKind::Level => pipeline.add(box LevelFilter::new(options.min_level.clone().unwrap(), options.level_rules.clone())),
+			FilterKind::Dedup => pipeline.add(box Dedup::new(options.dedup_window)),
+			FilterKind::Exec => {
+				let command = options.filter_exec.as_ref().unwrap();
+				match ExecFilter::spawn(command.as_slice()) {
+					Ok(filter) => pipeline.add(box filter),
+					Err(e) => return Err(format!("cannot start filter '{}': {}", command, e)),
+				}
 			},
-			Err(ErrMsg::Fatal) => break,
-			Err(ErrMsg::Retry) => continue,
+			FilterKind::Prefix => pipeline.add(box Prefixer::new(options.prefix.clone().unwrap(), stream, child, options.label.clone())),
+			FilterKind::Group => pipeline.add(box Grouper::new(options.group_by.clone().unwrap())),
 		}
+	}
+	Ok(pipeline)
+}
+
+// --flush-on: a byte as a number, 0 to 255 or 0x00 to 0xff, or as itself
+fn parse_byte(spec : &str) -> Option<u8> {
+	if spec.starts_with("0x") || spec.starts_with("0X") {
+		return num::from_str_radix::<u8>(spec.slice_from(2), 16);
+	}
+	match from_str::<u8>(spec) {
+		Some(byte) => Some(byte),
+		None if spec.len() == 1 => Some(spec.as_bytes()[0]),
+		None => None,
+	}
+}
+
+// --fd "3:L": a descriptor COMMAND opens a stream on later, and its mode
+fn parse_fd_mode(spec : &str) -> Result<(uint, BufferType), String> {
+	let idx = match spec.find(':') {
+		Some(idx) => idx,
+		None => return Err(format!("invalid --fd '{}', expected N:MODE", spec)),
 	};
-	if command_idx == -1 {
-		println!("Invalid options\nTry 'stdbuf --help' for more information.");
-		std::os::set_exit_status(125);
-		return;
+	let fd = match from_str::<uint>(spec.slice_to(idx)) {
+		Some(fd) => fd,
+		None => return Err(format!("invalid descriptor '{}' for --fd", spec.slice_to(idx))),
+	};
+	match from_str::<BufferType>(spec.slice_from(idx + 1)) {
+		Some(mode) => Ok((fd, mode)),
+		None => Err(format!("invalid mode '{}' for --fd", spec.slice_from(idx + 1))),
 	}
-	println!("{}", options);
+}
 
-	let ref command_name = args[command_idx];
-	let mut process = match Command::new(command_name).args(args.slice_from(command_idx+1)).spawn() {
-		Ok(p) => p,
-		Err(e) => panic!("failed to execute process: {}", e),
+// --after "30s:4M": the DURATION and the MODE our stdout changes to then
+fn parse_after(spec : &str) -> Result<(Duration, BufferType), String> {
+	let idx = match spec.find(':') {
+		Some(idx) => idx,
+		None => return Err(format!("invalid --after '{}', expected DURATION:MODE", spec)),
 	};
-	
-	let output = process.stdout.as_mut().unwrap().read_to_string().ok().expect("failed to read output");
-	println!("{}", output);
+	let after = match parse_duration(spec.slice_to(idx)) {
+		Some(after) => after,
+		None => return Err(format!("invalid duration '{}' for --after", spec.slice_to(idx))),
+	};
+	match from_str::<BufferType>(spec.slice_from(idx + 1)) {
+		Some(mode) => Ok((after, mode)),
+		None => Err(format!("invalid mode '{}' for --after", spec.slice_from(idx + 1))),
+	}
+}
+
+// --line-max only shortens what is displayed; sinks get every byte.
+fn relay(data : &[u8], stdout : &mut Option<Pump>, display : &mut Option<Truncator>, sinks : &mut Sinks, audit : &mut AuditLog) {
+	let failed = match *stdout {
+		Some(ref mut out) => match *display {
+			Some(ref mut truncator) => out.write(truncator.push(data).as_slice()).err(),
+			None => out.write(data).err(),
+		},
+		None => None,
+	};
+	// usually the reader went away, as with `| head`
+	if let Some(e) = failed {
+		audit.record("sink-error", &[("sink", "stdout".to_string()), ("error", e.to_string())]);
+		*stdout = None;
+	}
+	for (name, e) in sinks.write(data).into_iter() {
+		audit.record("sink-error", &[("sink", name), ("error", e.to_string())]);
+	}
+	stats::count(data);
+}
+
+// How our own stdout passes the output on. Unless libstdbuf cannot reach
+// COMMAND, it has already applied the mode there, so whatever arrives goes
+// straight out.
+fn pump_mode(options : &ProgramOptions, pumped : bool) -> pump::Mode {
+	// asked of the relay itself, whether or not libstdbuf reaches COMMAND
+	if let Some(byte) = options.flush_on {
+		return pump::Mode::Record(byte);
+	}
+	if !pumped {
+		return pump::Mode::Unbuffered;
+	}
+	pump_mode_of(&options.stdout)
+}
+
+fn pump_mode_of(mode : &BufferType) -> pump::Mode {
+	match *mode {
+		BufferType::Line => pump::Mode::Line,
+		BufferType::Size(n) => pump::Mode::Size(n as uint),
+		BufferType::Default | BufferType::Unbuffered => pump::Mode::Unbuffered,
+	}
+}
+
+// Combinations that are fine option by option but make no sense together.
+// Everything found is reported at once, each with a way out.
+fn conflicts(options : &ProgramOptions) -> Vec<String> {
+	let mut found = Vec::new();
+	if options.coalesce_stdin && options.stdin == BufferType::Unbuffered {
+		found.push("--coalesce-stdin with -i 0 passes every byte on as it comes; use -i L or -i SIZE".to_string());
+	}
+	if options.coalesce_stdin && options.stdin == BufferType::Default {
+		found.push("--coalesce-stdin needs a unit to gather input into; add -i L or -i SIZE".to_string());
+	}
+	if options.split_output.is_some() && options.stdout_file.is_none() && options.stdout_template.is_none() {
+		found.push("--split-output has no file to split; attach one with -o MODE:FILE or --stdout-file".to_string());
+	}
+	if options.frame_lines.is_some() {
+		if options.stdout_file.is_none() && options.stdout_template.is_none() {
+			found.push("--capture-format only applies to a file capture; attach one with -o MODE:FILE or --stdout-file".to_string());
+		}
+		if options.split_output.is_some() {
+			found.push("--split-output and --capture-format zstd-seekable both decide how the file is written; keep one".to_string());
+		}
+	}
+	if options.pipe_given && options.pty.is_none() {
+		found.push("--pipe only chooses which streams stay off the terminal; add --pty".to_string());
+	}
+	if options.hold_stdin && options.pty.is_none() {
+		found.push("--hold-stdin keeps the input of COMMAND's terminal open; add --pty".to_string());
+	}
+	if options.no_foreground && options.pty.is_none() {
+		found.push("--no-foreground only applies to the terminal --pty gives COMMAND; add --pty".to_string());
+	}
+	if options.flush_on.is_some() && options.stdout == BufferType::Line {
+		found.push("-o L and --flush-on both say where the output is passed on; keep one".to_string());
+	}
+	if options.fake_isatty.is_some() && options.pty.is_some() {
+		found.push("--pty already gives COMMAND a real terminal; drop --fake-isatty".to_string());
+	}
+	if let Some(option) = own_library_option(options) {
+		if cfg!(windows) {
+			found.push(format!("{} needs libstdbuf, which cannot be loaded into COMMAND on Windows", option));
+		}
+	}
+	if options.term_size && options.pty.is_some() {
+		found.push("--pty already gives COMMAND a terminal of the right size; drop --term-size".to_string());
+	}
+	if options.merge && options.pty.as_ref().map_or(false, |streams| streams.stdout && streams.stderr) {
+		found.push("with --pty, stdout and stderr already share the terminal; drop --merge or add --pipe stderr".to_string());
+	}
+	if options.tee_err && options.tee.is_none() {
+		found.push("--tee-err copies stderr into the --tee file; add --tee FILE".to_string());
+	}
+	if options.stdout_file.is_some() && options.stdout_template.is_some() {
+		found.push("the output is captured to a file by both -o MODE:FILE and --stdout-file; keep one".to_string());
+	}
+	if options.label.is_some() && options.prefix.is_none() {
+		found.push("--label is only used by --prefix; add e.g. --prefix '{label} '".to_string());
+	}
+	if !options.level_rules.is_empty() && options.min_level.is_none() {
+		found.push("--level-regex only classifies lines for --min-level; add --min-level LEVEL".to_string());
+	}
+	if let Some(ref order) = options.filter_order {
+		for kind in filter::DEFAULT_ORDER.iter() {
+			let listed = order.contains(kind);
+			let configured = kind == &FilterKind::StripAnsi || filter_configured(options, kind);
+			if listed && !configured {
+				found.push(format!("filter '{}' is listed in --filters but not configured; add its option", kind.name()));
+			} else if configured && !listed {
+				found.push(format!("filter '{}' is configured but missing from --filters; add it where it should run", kind.name()));
+			}
+		}
+	}
+	if options.raw {
+		for kind in filter::DEFAULT_ORDER.iter().filter(|k| filter_configured(options, *k)) {
+			found.push(format!("--raw relays the output unchanged, but filter '{}' would rewrite it; drop one of them", kind.name()));
+		}
+		if options.line_max.is_some() {
+			found.push("--raw relays the output unchanged, but --line-max would cut lines; drop one of them".to_string());
+		}
+	}
+	if options.watch {
+		for kind in filter::DEFAULT_ORDER.iter().filter(|k| filter_configured(options, *k)) {
+			found.push(format!("--watch passes the output on untouched, but filter '{}' would rewrite it; drop one of them", kind.name()));
+		}
+		if options.line_max.is_some() {
+			found.push("--watch passes the output on untouched, but --line-max would cut lines; drop one of them".to_string());
+		}
+	}
+	if options.capture_window.is_some() && options.stdout_file.is_none() && options.stdout_template.is_none() && options.tee_fd.is_none() && options.tee.is_none() {
+		found.push("--capture-window only limits what is copied; attach a copy with -o MODE:FILE, --stdout-file, --tee or --tee-fd".to_string());
+	}
+	if options.report_env && options.audit_log.is_none() {
+		found.push("--report-env writes to the audit log; add --audit-log FILE".to_string());
+	}
+	if options.progress_pattern.is_some() && options.progress_total.is_none() {
+		found.push("--progress-pattern only counts for the bar; add --progress-total N".to_string());
+	}
+	if let (Some(tee), Some(progress)) = (options.tee_fd, options.progress_fd) {
+		if tee == progress {
+			found.push(format!("--tee-fd and --progress-fd both use descriptor {}; give the progress reports their own", tee));
+		}
+	}
+	found
 }
 
+fn parse_options(args : &[String], options : &mut ProgramOptions, optgrps : &[OptGroup]) -> Result<OkMsg, ErrMsg> {
+	// getopts would take the argument after a bare --timestamp or --dedup,
+	// COMMAND most likely, for its value; command_index does not
+	let args : Vec<String> = args.iter().map(|arg| {
+		let bare = arg.starts_with("--") && optgrps.iter().any(|g| {
+			g.hasarg == HasArg::Maybe && !g.long_name.is_empty() && arg.slice_from(2) == g.long_name.as_slice()
+		});
+		if bare { format!("{}=", arg) } else { arg.clone() }
+	}).collect();
+	let args = args.as_slice();
+	let matches = match getopts(args, optgrps) {
+		Ok(m) => m,
+		Err(_) => return Err(ErrMsg::Invalid)
+	};
+	if matches.opt_present("help") {
+		return Ok(OkMsg::Help);
+	}
+	if matches.opt_present("version") {
+		return Ok(OkMsg::Version);
+	}
+	// split in main already when given first
+	if matches.opt_present("split-string") {
+		println!("stdbuf: -S must be the first option");
+		return Err(ErrMsg::Fatal);
+	}
+	let mut modified = false;
+	let mut stdout_file = None;
+	options.stdin = try!(check_option(&matches, "input", &mut modified, &mut None).ok_or(ErrMsg::Fatal));
+	options.stdout = try!(check_option(&matches, "output", &mut modified, &mut stdout_file).ok_or(ErrMsg::Fatal));
+	options.stderr = try!(check_option(&matches, "error", &mut modified, &mut None).ok_or(ErrMsg::Fatal));
+	options.stdout_file = stdout_file;
+	// --all fills in the streams not given on their own; "L" leaves stdin
+	// alone, as that would put a pump of ours in front of it
+	if let Some(spec) = matches.opt_str("all") {
+		let mode = match from_str::<BufferType>(spec.as_slice()) {
+			Some(mode) => mode,
+			None => {
+				println!("stdbuf: {} for --all", parse_size(spec.as_slice()).err().unwrap());
+				return Err(ErrMsg::Fatal);
+			}
+		};
+		modified = true;
+		if options.stdin == BufferType::Default && mode != BufferType::Line {
+			options.stdin = mode.clone();
+		}
+		if options.stdout == BufferType::Default {
+			options.stdout = mode.clone();
+		}
+		if options.stderr == BufferType::Default {
+			options.stderr = mode;
+		}
+	}
+	options.stdout_template = matches.opt_str("stdout-file");
+	options.tee = matches.opt_str("tee").map(|file| Path::new(file));
+	options.tee_err = matches.opt_present("tee-err");
+	options.merge = matches.opt_present("merge");
+	options.only_for = matches.opt_str("only-for");
+	options.env_only = matches.opt_present("env-only");
+	options.depth = match matches.opt_str("depth") {
+		Some(n) => match from_str::<uint>(n.as_slice()) {
+			Some(n) if n > 0 => Some(n),
+			_ => {
+				println!("stdbuf: invalid --depth '{}', expected a number of programs from 1 up", n);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.frame_lines = match matches.opt_str("capture-format") {
+		None => None,
+		Some(ref format) if format.as_slice() == "plain" => None,
+		Some(format) => {
+			let lines = match format.as_slice() {
+				"zstd-seekable" => Some(1000),
+				f if f.starts_with("zstd-seekable:") => from_str::<uint>(f.slice_from(14)).and_then(|n| if n > 0 { Some(n) } else { None }),
+				_ => None,
+			};
+			match lines {
+				Some(_) if !seekable::SUPPORTED => {
+					println!("stdbuf: zstd-seekable captures are not supported by this build");
+					return Err(ErrMsg::Fatal);
+				},
+				Some(n) => Some(n),
+				None => {
+					println!("stdbuf: invalid --capture-format '{}', expected plain or zstd-seekable[:LINES]", format);
+					return Err(ErrMsg::Fatal);
+				}
+			}
+		}
+	};
+	if let Some(ref template) = options.stdout_template {
+		// only to report a bad template now rather than after starting up
+		if let Err(e) = sink::expand_name(template.as_slice(), "cmd") {
+			println!("stdbuf: {}", e);
+			return Err(ErrMsg::Fatal);
+		}
+	}
+	options.audit_log = matches.opt_str("audit-log").map(|p| Path::new(p));
+	options.landlock = match matches.opt_str("landlock") {
+		Some(spec) => {
+			if !landlock::SUPPORTED {
+				println!("stdbuf: --landlock is not supported by this build");
+				return Err(ErrMsg::Fatal);
+			}
+			match Ruleset::parse(spec.as_slice()) {
+				Ok(rules) => Some(rules),
+				Err(e) => {
+					println!("stdbuf: {}", e);
+					return Err(ErrMsg::Fatal);
+				}
+			}
+		},
+		None => None,
+	};
+	options.oom_score_adj = match matches.opt_str("oom-score-adj") {
+		Some(value) => match from_str::<int>(value.as_slice()) {
+			Some(n) if n >= -1000 && n <= 1000 => Some(n),
+			_ => {
+				println!("stdbuf: invalid oom score adjustment '{}', expected -1000..1000", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.send_on = Vec::new();
+	for spec in matches.opt_strs("send-on").iter() {
+		match SendRule::parse(spec.as_slice()) {
+			Ok(rule) => options.send_on.push(rule),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	options.validate = matches.opt_present("validate");
+	options.verbose = matches.opt_present("verbose");
+	options.dedup = matches.opt_present("dedup");
+	options.dedup_window = match matches.opt_str("dedup") {
+		Some(ref value) if value.is_empty() => None,
+		Some(value) => match from_str::<uint>(value.as_slice()) {
+			Some(n) if n > 0 => Some(n),
+			_ => {
+				println!("stdbuf: invalid --dedup window '{}'", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.min_level = match matches.opt_str("min-level") {
+		Some(name) => match Level::parse(name.as_slice()) {
+			Some(level) => Some(level),
+			None => {
+				println!("stdbuf: invalid level '{}', expected trace, debug, info, warn or error", name);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.level_rules = Vec::new();
+	for spec in matches.opt_strs("level-regex").iter() {
+		match LevelRule::parse(spec.as_slice()) {
+			Ok(rule) => options.level_rules.push(rule),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	options.group_by = match matches.opt_str("group-by") {
+		Some(pattern) => match Regex::new(pattern.as_slice()) {
+			Ok(re) => Some(re),
+			Err(e) => {
+				println!("stdbuf: invalid --group-by pattern '{}': {}", pattern, e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.redact = Vec::new();
+	for spec in matches.opt_strs("redact").iter() {
+		match RedactRule::parse(spec.as_slice()) {
+			Ok(rule) => options.redact.push(rule),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	if matches.opt_present("redact-secrets") {
+		options.redact.extend(RedactRule::builtin().into_iter());
+	}
+	options.tee_fd = match matches.opt_str("tee-fd") {
+		Some(value) => match from_str::<libc::c_int>(value.as_slice()) {
+			Some(fd) if fd > 2 => Some(fd),
+			_ => {
+				println!("stdbuf: invalid descriptor '{}' for --tee-fd, expected a number above 2", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.report_env = matches.opt_present("report-env");
+	let streams = match pty::Streams::parse(matches.opt_strs("pipe").as_slice()) {
+		Ok(streams) => streams,
+		Err(e) => {
+			println!("stdbuf: {}", e);
+			return Err(ErrMsg::Fatal);
+		}
+	};
+	options.pipe_given = matches.opt_present("pipe");
+	options.pty = if matches.opt_present("pty") { Some(streams) } else { None };
+	options.hold_stdin = matches.opt_present("hold-stdin");
+	options.no_foreground = matches.opt_present("no-foreground");
+	options.term_size = matches.opt_present("term-size");
+	// libstdbuf can do nothing useful with a line buffered stdin, so that
+	// is always gathered into lines by us
+	options.coalesce_stdin = matches.opt_present("coalesce-stdin") || options.stdin == BufferType::Line;
+	options.fd_modes = Vec::new();
+	for spec in matches.opt_strs("fd").iter() {
+		match parse_fd_mode(spec.as_slice()) {
+			Ok(fd_mode) => options.fd_modes.push(fd_mode),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	options.all_streams = match matches.opt_str("all-streams") {
+		Some(spec) => match from_str::<BufferType>(spec.as_slice()) {
+			Some(mode) => Some(mode),
+			None => {
+				println!("stdbuf: {} for --all-streams", parse_size(spec.as_slice()).err().unwrap());
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.trace_io = matches.opt_str("trace-io").map(|file| os::make_absolute(&Path::new(file)));
+	options.flush_on = match matches.opt_str("flush-on") {
+		Some(spec) => match parse_byte(spec.as_slice()) {
+			Some(byte) => Some(byte),
+			None => {
+				println!("stdbuf: invalid --flush-on '{}', expected a byte such as 0, 0x1e or ;", spec);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.flush_interval = match matches.opt_str("flush-interval") {
+		Some(value) => match parse_duration(value.as_slice()) {
+			Some(d) if d > Duration::zero() => Some(d),
+			_ => {
+				println!("stdbuf: invalid duration '{}' for --flush-interval", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.fake_isatty = match matches.opt_str("fake-isatty") {
+		Some(list) => match pty::Streams::named(list.as_slice(), "--fake-isatty") {
+			Ok(streams) => Some(streams),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.on_stderr = Vec::new();
+	for spec in matches.opt_strs("on-stderr-pattern").iter() {
+		match Trigger::parse(spec.as_slice()) {
+			Ok(trigger) => options.on_stderr.push(trigger),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	options.shape = match matches.opt_str("shape") {
+		Some(spec) => match parse_shape(spec.as_slice()) {
+			Ok(shape) => Some(shape),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.progress_total = match matches.opt_str("progress-total") {
+		Some(value) => match from_str::<uint>(value.as_slice()) {
+			Some(n) => Some(n),
+			None => {
+				println!("stdbuf: invalid count '{}' for --progress-total", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.progress_pattern = match matches.opt_str("progress-pattern") {
+		Some(value) => match Regex::new(value.as_slice()) {
+			Ok(re) => Some(re),
+			Err(e) => {
+				println!("stdbuf: invalid --progress-pattern '{}': {}", value, e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.progress_fd = match matches.opt_str("progress-fd") {
+		Some(value) => match from_str::<libc::c_int>(value.as_slice()) {
+			Some(fd) if fd > 2 => Some(fd),
+			_ => {
+				println!("stdbuf: invalid descriptor '{}' for --progress-fd, expected a number above 2", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.split_output = match matches.opt_str("split-output") {
+		Some(value) => match parse_size(value.as_slice()) {
+			Ok(size) if size > 0 => Some(size),
+			Ok(_) => {
+				println!("stdbuf: invalid size '{}' for --split-output", value);
+				return Err(ErrMsg::Fatal);
+			},
+			Err(e) => {
+				println!("stdbuf: {} for --split-output", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.prefix = match matches.opt_str("prefix") {
+		Some(spec) => match Template::parse(spec.as_slice()) {
+			Ok(template) => Some(template),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	if matches.opt_present("timestamp") {
+		let format = match matches.opt_str("timestamp") {
+			Some(ref format) if !format.is_empty() => format.clone(),
+			_ => prefix::TIMESTAMP_FORMAT.to_string(),
+		};
+		options.prefix = match Template::timestamped(format.as_slice(), options.prefix.take()) {
+			Ok(template) => Some(template),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		};
+	}
+	options.label = matches.opt_str("label");
+	options.line_max = match matches.opt_str("line-max") {
+		Some(value) => match from_str::<uint>(value.as_slice()) {
+			Some(n) if n > 1 => Some(n),
+			_ => {
+				println!("stdbuf: invalid width '{}' for --line-max", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.notify_ready = match matches.opt_str("notify-ready") {
+		Some(pattern) => match Regex::new(pattern.as_slice()) {
+			Ok(re) => Some(re),
+			Err(e) => {
+				println!("stdbuf: invalid --notify-ready pattern '{}': {}", pattern, e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.notify_systemd = matches.opt_present("notify-systemd") || options.notify_ready.is_some();
+	options.fake_clock = match matches.opt_str("fake-clock") {
+		Some(spec) => match clock::parse_fake(spec.as_slice()) {
+			Ok(fake) => Some(fake),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.stop_signal = match matches.opt_str("stop-signal") {
+		Some(name) => match shutdown::parse_signal(name.as_slice()) {
+			Some(signum) => Some(signum),
+			None => {
+				println!("stdbuf: invalid signal '{}'", name);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.stats_signal = match matches.opt_str("stats-signal") {
+		Some(ref name) if name.as_slice() == "none" => None,
+		Some(name) => match shutdown::parse_signal(name.as_slice()) {
+			Some(signum) => Some(signum),
+			None => {
+				println!("stdbuf: invalid signal '{}'", name);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => Some(STATS_SIGNAL),
+	};
+	options.watch = matches.opt_present("watch");
+	options.heartbeat = match matches.opt_str("heartbeat") {
+		Some(value) => match parse_duration(value.as_slice()) {
+			Some(d) if d > Duration::zero() => Some(d),
+			_ => {
+				println!("stdbuf: invalid duration '{}' for --heartbeat", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.timeout = match matches.opt_str("timeout") {
+		Some(value) => match parse_duration(value.as_slice()) {
+			Some(d) => Some(d),
+			None => {
+				println!("stdbuf: invalid duration '{}' for --timeout", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.stop_timeout = match matches.opt_str("stop-timeout") {
+		Some(value) => match parse_duration(value.as_slice()) {
+			Some(d) => Some(d),
+			None => {
+				println!("stdbuf: invalid duration '{}' for --stop-timeout", value);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.filter_order = match matches.opt_str("filters") {
+		Some(spec) => match FilterKind::parse_order(spec.as_slice()) {
+			Ok(order) => Some(order),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	// listing strip-ansi is enough to enable it
+	options.strip_ansi = matches.opt_present("strip-ansi") || options.filter_order.as_ref().map_or(false, |o| o.contains(&FilterKind::StripAnsi));
+	options.filter_exec = matches.opt_str("filter-exec");
+	options.raw = matches.opt_present("raw");
+	if matches.opt_present("close-fds") && matches.opt_present("inherit-fds") {
+		println!("stdbuf: --close-fds and --inherit-fds are mutually exclusive");
+		return Err(ErrMsg::Fatal);
+	}
+	options.inherit_fds = matches.opt_present("inherit-fds");
+	options.after = match matches.opt_str("after") {
+		Some(spec) => match parse_after(spec.as_slice()) {
+			Ok(after) => Some(after),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	options.capture_window = match matches.opt_str("capture-window") {
+		Some(spec) => {
+			let spec = spec.as_slice();
+			let bounds = match spec.find_str("..") {
+				Some(idx) => parse_bound(spec.slice_to(idx)).and_then(|start| {
+					let end = spec.slice_from(idx + 2);
+					if end.is_empty() {
+						Ok((start, None))
+					} else {
+						parse_bound(end).map(|end| (start, Some(end)))
+					}
+				}),
+				None => parse_bound(spec).map(|start| (start, None)),
+			};
+			match bounds {
+				Ok(bounds) => Some(bounds),
+				Err(e) => {
+					println!("stdbuf: {}", e);
+					return Err(ErrMsg::Fatal);
+				}
+			}
+		},
+		None => None,
+	};
+	options.entrypoint = matches.opt_present("entrypoint");
+	options.env_file = matches.opt_str("env-file").map(|p| Path::new(p));
+	options.drop_caps = match matches.opt_str("drop-caps") {
+		Some(spec) => match CapSet::parse(spec.as_slice()) {
+			Ok(set) => Some(set),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		},
+		None => None,
+	};
+	
+	for spec in matches.opt_strs("service").iter() {
+		match Service::parse(spec.as_slice()) {
+			Ok(service) => options.services.push(service),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				return Err(ErrMsg::Fatal);
+			}
+		}
+	}
+	options.service_logs = matches.opt_str("service-logs").map(|dir| Path::new(dir));
+	if options.service_logs.is_some() && options.services.is_empty() {
+		println!("stdbuf: --service-logs needs --service");
+		return Err(ErrMsg::Fatal);
+	}
+	
+	if !options.services.is_empty() {
+		if !matches.free.is_empty() {
+			println!("stdbuf: --service runs its own commands; give COMMAND as another --service");
+			return Err(ErrMsg::Fatal);
+		}
+	} else if matches.free.len() != 1 {
+		return Err(ErrMsg::Invalid);
+	}
+	if !modified && options.services.is_empty() && options.fake_isatty.is_none() && options.flush_on.is_none() && options.trace_io.is_none()
+		&& options.fd_modes.is_empty() && options.all_streams.is_none() {
+		println!("stdbuf: you must specify a buffering mode option");
+		return Err(ErrMsg::Fatal);
+	}
+	let problems = conflicts(options);
+	if !problems.is_empty() {
+		for problem in problems.iter() {
+			println!("stdbuf: {}", problem);
+		}
+		return Err(ErrMsg::Fatal);
+	}
+	Ok(OkMsg::Buffering)
+}
+
+// `stdbuf [OPTION]... run --and CMD [--and CMD]... [--keep-going]
+// [--status first-failure|worst]`: the commands run one after the other
+// under the same buffering and sinks. A program that is really called
+// `run` can still be reached as ./run or by its full path.
+struct RunPlan {
+	keep_going : bool,
+	worst : bool,
+}
+
+impl RunPlan {
+	// first-failure: the status of the first command that failed, the
+	// default; worst: the highest status of all. Both are 0 if nothing
+	// failed, and they only differ with --keep-going.
+	fn status(&self, results : &[(String, int)]) -> int {
+		if self.worst {
+			results.iter().map(|&(_, code)| code).max().unwrap_or(0)
+		} else {
+			results.iter().map(|&(_, code)| code).find(|&code| code != 0).unwrap_or(0)
+		}
+	}
+}
+
+fn parse_run(args : &[String]) -> Result<(RunPlan, Vec<Vec<String>>), String> {
+	let optgrps = [
+		optmulti("", "and", "", "CMD"),
+		optflag("", "keep-going", ""),
+		optopt("", "status", "", "POLICY"),
+	];
+	let matches = match getopts(args, &optgrps) {
+		Ok(m) => m,
+		Err(e) => return Err(format!("run: {}", e)),
+	};
+	if !matches.free.is_empty() {
+		return Err(format!("run: unexpected '{}'; give every command with --and", matches.free[0]));
+	}
+	let worst = match matches.opt_str("status") {
+		None => false,
+		Some(ref policy) if policy.as_slice() == "first-failure" => false,
+		Some(ref policy) if policy.as_slice() == "worst" => true,
+		Some(policy) => return Err(format!("run: invalid --status '{}', expected first-failure or worst", policy)),
+	};
+	let mut runs = Vec::new();
+	for cmd in matches.opt_strs("and").iter() {
+		match init::split_words(cmd.as_slice()) {
+			Ok(ref words) if words.is_empty() => return Err("run: empty command given to --and".to_string()),
+			Ok(words) => runs.push(words),
+			Err(e) => return Err(format!("run: {}", e)),
+		}
+	}
+	if runs.is_empty() {
+		return Err("run: no commands; give them with --and CMD".to_string());
+	}
+	Ok((RunPlan { keep_going : matches.opt_present("keep-going"), worst : worst }, runs))
+}
+
+fn report_runs(results : &[(String, int)], planned : uint, audit : &mut AuditLog) {
+	let mut err = std::io::stdio::stderr();
+	for (i, &(ref cmd, code)) in results.iter().enumerate() {
+		let _ = writeln!(&mut err, "stdbuf: [{}/{}] {}: exit {}", i + 1, planned, cmd, code);
+	}
+	if results.len() < planned {
+		let _ = writeln!(&mut err, "stdbuf: {} of {} commands not run", planned - results.len(), planned);
+	}
+	let failed = results.iter().filter(|&&(_, code)| code != 0).count();
+	audit.record("run-summary", &[("planned", planned.to_string()), ("run", results.len().to_string()), ("failed", failed.to_string())]);
+}
+
+// How one COMMAND ended, as our own exit status would report it.
+struct Outcome {
+	code : int,
+	// by --on-stderr-pattern
+	marked_failed : bool,
+	restart : bool,
+}
+
+// Everything the output passes through that outlives a single COMMAND, so
+// the commands of `stdbuf run` read as one stream.
+struct Relay {
+	stdout : Option<Pump>,
+	display : Option<Truncator>,
+	sinks : Sinks,
+	progress : Option<Progress>,
+	bar : Option<ProgressBar>,
+	shaper : Option<Shaper>,
+	ready_watch : Option<Responder>,
+	notifier : Option<Notifier>,
+	// --tee-err: where COMMAND's stderr is copied besides our own
+	stderr_copy : Option<SharedFile>,
+	// --merge: where COMMAND's stderr goes instead of our own
+	merged : Option<pump::Merged>,
+	oom_score : Option<OomScore>,
+}
+
+fn exit_code(status : ProcessExit) -> int {
+	match status {
+		ExitStatus(code) => code,
+		ExitSignal(signum) => 128 + signum,
+	}
+}
+
+fn run_command(argv : &[String], options : &ProgramOptions, overrides : &[(String, String)], inherited : &[libc::c_int], r : &mut Relay, audit : &mut AuditLog) -> Outcome {
+	let ref command_name = argv[0];
+	let mut terminal = None;
+	let mut command = if let Some(ref streams) = options.pty {
+		match pty::Pty::open() {
+			Ok(p) => {
+				// see pty.rs for why COMMAND is not started directly
+				let mut command = Command::new(os::self_exe_name().unwrap_or(Path::new("stdbuf")));
+				command.arg(if options.no_foreground { "__pty-exec-background" } else { "__pty-exec" }).arg(command_name.as_slice());
+				if streams.stdin {
+					command.stdin(InheritFd(p.slave));
+				}
+				if streams.stdout {
+					command.stdout(InheritFd(p.slave));
+				}
+				if streams.stderr {
+					command.stderr(InheritFd(p.slave));
+				}
+				terminal = Some(p);
+				command
+			},
+			Err(e) => {
+				audit.record("pty-failed", &[("error", e.to_string())]);
+				let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: cannot allocate a pseudo-terminal: {}", e);
+				return Outcome { code : 125, marked_failed : false, restart : false };
+			}
+		}
+	} else {
+		command_for(command_name.as_slice())
+	};
+	command.args(argv.slice_from(1));
+	for &(ref key, ref value) in overrides.iter() {
+		command.env(key.as_slice(), value.as_slice());
+	}
+	// `stdbuf __pty-exec` is a generation of its own
+	if let Some(depth) = options.depth {
+		if terminal.is_some() && overrides.iter().any(|&(ref key, _)| key.as_slice() == "_STDBUF_DEPTH") {
+			command.env("_STDBUF_DEPTH", (depth + 1).to_string());
+		}
+	}
+	// libstdbuf reports its problems to us on the first descriptor past the
+	// inherited ones. Not through --pty: `stdbuf __pty-exec` would load the
+	// library first and take the descriptor away from COMMAND.
+	let wants_channel = terminal.is_none() && overrides.iter().any(|&(ref key, _)| key.as_slice() == "_STDBUF_PROTO");
+	let channel = if wants_channel { preload::ErrorChannel::open().ok() } else { None };
+	let err_fd = inherited.last().map_or(3, |&fd| fd + 1);
+	if channel.is_some() {
+		command.env("_STDBUF_ERRFD", err_fd.to_string());
+	}
+	// The child only ever gets stdin, stdout and stderr unless told otherwise.
+	// Extra descriptors are handed over by position from 3 up, so the gaps
+	// between inherited ones are filled with /dev/null to keep each at its
+	// number.
+	let last = if channel.is_some() { Some(err_fd) } else { inherited.last().map(|&fd| fd) };
+	if let Some(last) = last {
+		let extra : Vec<StdioContainer> = range_inclusive(3, last).map(|fd| {
+			match channel {
+				Some(ref channel) if fd == err_fd => InheritFd(channel.write),
+				_ if inherited.contains(&fd) => InheritFd(fd),
+				_ => Ignored,
+			}
+		}).collect();
+		command.extra_io(extra.as_slice());
+	}
+	// Only stdout is relayed. stderr is the child's own unless it has to be
+	// watched, copied, merged or redacted, and so is stdin unless --send-on
+	// has to write to it or it is coalesced. With --pty the streams are the
+	// terminal or, left out of it, pipes of ours; a stderr we read is always
+	// a pipe.
+	if !options.on_stderr.is_empty() || options.tee_err || options.merge || !options.redact.is_empty() {
+		command.stderr(CreatePipe(false, true));
+	} else if terminal.is_none() {
+		command.stderr(InheritFd(2));
+	}
+	if options.send_on.is_empty() && terminal.is_none() && !options.coalesce_stdin {
+		command.stdin(InheritFd(0));
+	}
+	// a pty child leads a session of its own, with the pty as its terminal
+	let own_group = terminal.is_some() || !shutdown::in_foreground();
+	command.detached(own_group);
+	if let Some(ref mut score) = r.oom_score {
+		if let Err(e) = score.for_child() {
+			audit.record("oom-score-adj-failed", &[("error", e.to_string())]);
+			let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: cannot set oom_score_adj to {}: {}", score.child, e);
+		}
+	}
+	let spawned = command.spawn();
+	if let Some(ref mut score) = r.oom_score {
+		if let Err(e) = score.restore() {
+			audit.record("oom-score-adj-failed", &[("error", e.to_string())]);
+		}
+	}
+	let mut process = match spawned {
+		Ok(p) => p,
+		// the standard library reports the errno of a failed exec back over
+		// a close-on-exec pipe, so this is the real reason, not a guess
+		Err(e) => {
+			audit.record("exec-failed", &[("error", e.to_string())]);
+			println!("stdbuf: exec {}: {}", command_name, e);
+			// as env(1) and nohup(1): 127 when COMMAND was not found, 126
+			// when it could not be invoked
+			let code = match e.kind {
+				FileNotFound => 127,
+				_ => 126,
+			};
+			return Outcome { code : code, marked_failed : false, restart : false };
+		}
+	};
+	audit.record("exec", &[("child", process.id().to_string())]);
+	if let Some(channel) = channel {
+		channel.report(command_name.clone());
+	}
+	stats::started();
+	shutdown::set_child_group(own_group);
+	shutdown::set_child(process.id());
+	let as_init = options.entrypoint && unsafe { libc::getpid() } == 1;
+	if as_init {
+		init::start_reaper(process.id());
+	}
+	if let Some(signum) = shutdown::pending_stop_signal() {
+		// arrived before the child was known to the handler
+		let _ = process.signal(signum);
+	}
+	
+	let mut responder = Responder::new(options.send_on.clone());
+	let mut pipeline = match build_pipeline(options, process.id(), "stdout") {
+		Ok(pipeline) => pipeline,
+		Err(e) => {
+			println!("stdbuf: {}", e);
+			audit.record("filter-exec-failed", &[("error", e.clone())]);
+			let _ = process.signal_kill();
+			let _ = process.wait();
+			shutdown::set_child(0);
+			return Outcome { code : 125, marked_failed : false, restart : false };
+		}
+	};
+	let mut output = process.stdout.take();
+	let mut child_stdin = process.stdin.take().map(|stdin| Arc::new(Mutex::new(stdin)));
+	let mut pty_control = None;
+	let mut terminal_stderr = None;
+	if let Some(ref mut p) = terminal {
+		p.close_slave();
+		let streams = options.pty.as_ref().unwrap();
+		if streams.stdin {
+			if let Ok(writer) = p.writer() {
+				child_stdin = Some(Arc::new(Mutex::new(writer)));
+				if !options.hold_stdin {
+					pty_control = p.control().ok();
+				}
+			}
+		}
+		match p.reader() {
+			Ok(reader) if streams.stdout => output = Some(reader),
+			// the terminal carries COMMAND's stderr, or nothing at all
+			Ok(reader) => terminal_stderr = Some(pty::copy_to_stderr(reader)),
+			Err(_) => {},
+		}
+	}
+	// The forwarding thread holds the only handle unless --send-on has
+	// responses still to write or --hold-stdin keeps it, so otherwise
+	// COMMAND sees EOF once our stdin ends. Held, it is closed only when
+	// the run is over, and the terminal is not sent its EOF character.
+	if let Some(stdin) = child_stdin.take() {
+		if !options.send_on.is_empty() || options.hold_stdin {
+			child_stdin = Some(stdin.clone());
+		}
+		let mode = if options.coalesce_stdin { pump_mode_of(&options.stdin) } else { pump::Mode::Unbuffered };
+		expect::forward_stdin(stdin, mode, pty_control);
+	}
+	let stderr_watch = process.stderr.take().map(|err| {
+		let out : Box<Writer + Send> = match r.merged {
+			Some(ref merged) => box merged.clone(),
+			None => box std::io::stdio::stderr_raw(),
+		};
+		// only stages that cannot fail to start
+		let filters = build_pipeline(options, process.id(), "stderr").unwrap_or(Pipeline::new());
+		StderrWatch::start(err, options.on_stderr.clone(), process.id(), filters, out, r.stderr_copy.clone())
+	});
+	let mut buf = [0u8, ..4096];
+	while output.is_some() {
+		let n = match output.as_mut().unwrap().read(&mut buf) {
+			Ok(n) => n,
+			Err(ref e) if e.kind == EndOfFile => break,
+			// how the master reports that COMMAND's side has been closed
+			Err(_) if terminal.is_some() => break,
+			Err(e) => {
+				// keep what was already read rather than losing it to a panic
+				audit.record("sink-error", &[("sink", "relay".to_string()), ("error", e.to_string())]);
+				break;
+			}
+		};
+		if let Some(ref p) = r.progress {
+			p.count(buf.slice_to(n));
+		}
+		if let Some(ref mut b) = r.bar {
+			b.count(buf.slice_to(n));
+		}
+		if let Some(ref mut s) = r.shaper {
+			s.pace(n);
+		}
+		if pipeline.is_empty() {
+			relay(buf.slice_to(n), &mut r.stdout, &mut r.display, &mut r.sinks, audit);
+		} else {
+			for out in pipeline.push(buf.slice_to(n)).iter() {
+				relay(out.as_slice(), &mut r.stdout, &mut r.display, &mut r.sinks, audit);
+			}
+		}
+		if r.stdout.is_none() {
+			// closing our end makes COMMAND's next write fail with SIGPIPE,
+			// as if it had been writing into the pipe itself
+			output = None;
+			break;
+		}
+		let became_ready = match r.ready_watch {
+			Some(ref mut watch) => !watch.feed(buf.slice_to(n)).is_empty(),
+			None => false,
+		};
+		if became_ready {
+			r.ready_watch = None;
+			if let Some(ref n) = r.notifier {
+				n.notify("READY=1");
+			}
+			audit.record("ready", &[]);
+		}
+		for idx in responder.feed(buf.slice_to(n)).into_iter() {
+			let rule = responder.rule(idx);
+			audit.record("send-on", &[("pattern", rule.pattern.to_string())]);
+			if let Some(ref stdin) = child_stdin {
+				let mut stdin = stdin.lock();
+				let _ = stdin.write_str(rule.response.as_slice()).and_then(|_| stdin.flush());
+			}
+		}
+	}
+	for out in pipeline.finish().iter() {
+		relay(out.as_slice(), &mut r.stdout, &mut r.display, &mut r.sinks, audit);
+	}
+	let status = process.wait();
+	if let Some(copy) = terminal_stderr {
+		let _ = copy.join();
+	}
+	let code = match status {
+		Ok(status) => {
+			audit.record("exit", &[("status", status.to_string())]);
+			if options.report_env && !status.success() {
+				record_env(audit, overrides, options);
+			}
+			exit_code(status)
+		},
+		Err(e) => {
+			audit.record("wait-failed", &[("error", e.to_string())]);
+			125
+		},
+	};
+	shutdown::set_child(0);
+	if as_init {
+		init::reap_orphans();
+	}
+	let (marked_failed, restart) = match stderr_watch {
+		Some(watch) => watch.finish(),
+		None => (false, false),
+	};
+	Outcome { code : code, marked_failed : marked_failed, restart : restart }
+}
+
+// Passes on only the lines that match, for `stdbuf cat --grep`; it is
+// written one whole line at a time.
+struct GrepWriter<'a> {
+	pattern : Option<Regex>,
+	out : &'a mut (Writer + 'a),
+}
+
+impl<'a> Writer for GrepWriter<'a> {
+	fn write(&mut self, line : &[u8]) -> IoResult<()> {
+		let keep = match self.pattern {
+			Some(ref re) => re.is_match(String::from_utf8_lossy(line).as_slice()),
+			None => true,
+		};
+		if keep { self.out.write(line) } else { Ok(()) }
+	}
+}
+
+// Lines `first` to `last` of a plain capture, as cat_range does for a
+// seekable one.
+fn cat_plain(path : &Path, first : uint, last : Option<uint>, out : &mut Writer) -> IoResult<()> {
+	let mut reader = BufferedReader::new(try!(File::open(path)));
+	let mut line = 1u;
+	loop {
+		if last.map_or(false, |last| line > last) {
+			return Ok(());
+		}
+		let text = match reader.read_until(b'\n') {
+			Ok(text) => text,
+			Err(ref e) if e.kind == EndOfFile => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		if line >= first {
+			try!(out.write(text.as_slice()));
+		}
+		line += 1;
+	}
+}
+
+// `stdbuf cat [--range START:END] [--grep RE] FILE`: prints lines of a
+// capture, plain or zstd-seekable, counted from 1; either end of the range
+// may be left out. With --grep only the lines in range that match are kept.
+fn cat_main(args : &[String]) {
+	let optgrps = [
+		optopt("", "range", "", "START:END"),
+		optopt("", "grep", "", "RE"),
+	];
+	let matches = match getopts(args, &optgrps) {
+		Ok(m) => m,
+		Err(e) => {
+			println!("stdbuf: cat: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+	};
+	if matches.free.len() != 1 {
+		println!("stdbuf: cat: expected exactly one FILE");
+		std::os::set_exit_status(125);
+		return;
+	}
+	let range = matches.opt_str("range").unwrap_or(":".to_string());
+	let bounds = match range.as_slice().find(':') {
+		Some(idx) => {
+			let (start, end) = (range.as_slice().slice_to(idx), range.as_slice().slice_from(idx + 1));
+			let first = if start.is_empty() { Some(1) } else { from_str::<uint>(start).and_then(|n| if n > 0 { Some(n) } else { None }) };
+			let last = if end.is_empty() { Some(None) } else { from_str::<uint>(end).map(|n| Some(n)) };
+			first.and_then(|first| last.map(|last| (first, last)))
+		},
+		None => None,
+	};
+	let (first, last) = match bounds {
+		Some(bounds) => bounds,
+		None => {
+			println!("stdbuf: cat: invalid range '{}', expected START:END with lines counted from 1", range);
+			std::os::set_exit_status(125);
+			return;
+		}
+	};
+	let pattern = match matches.opt_str("grep") {
+		Some(re) => match Regex::new(re.as_slice()) {
+			Ok(re) => Some(re),
+			Err(e) => {
+				println!("stdbuf: cat: invalid --grep pattern '{}': {}", re, e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		},
+		None => None,
+	};
+	let path = Path::new(matches.free[0].as_slice());
+	let mut stdout = std::io::stdio::stdout_raw();
+	let mut out = GrepWriter { pattern : pattern, out : &mut stdout };
+	let result = if seekable::is_seekable(&path) {
+		seekable::cat_range(&path, first, last, &mut out)
+	} else {
+		cat_plain(&path, first, last, &mut out)
+	};
+	if let Err(e) = result {
+		println!("stdbuf: cat: {}: {}", path.display(), e);
+		std::os::set_exit_status(125);
+	}
+}
+
+// `stdbuf tail [-f] [-n LINES] FILE`: the end of a capture, following it
+// across the parts of --split-output with -f.
+fn tail_main(args : &[String]) {
+	let optgrps = [
+		optflag("f", "follow", ""),
+		optopt("n", "lines", "", "LINES"),
+	];
+	let matches = match getopts(args, &optgrps) {
+		Ok(m) => m,
+		Err(e) => {
+			println!("stdbuf: tail: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+	};
+	if matches.free.len() != 1 {
+		println!("stdbuf: tail: expected exactly one FILE");
+		std::os::set_exit_status(125);
+		return;
+	}
+	let lines = match matches.opt_str("lines") {
+		Some(n) => match from_str::<uint>(n.as_slice()) {
+			Some(n) => n,
+			None => {
+				println!("stdbuf: tail: invalid number of lines '{}'", n);
+				std::os::set_exit_status(125);
+				return;
+			}
+		},
+		None => 10,
+	};
+	let path = Path::new(matches.free[0].as_slice());
+	let mut out = std::io::stdio::stdout_raw();
+	if let Err(e) = tail::tail(&path, lines, matches.opt_present("follow"), &mut out) {
+		println!("stdbuf: tail: {}: {}", path.display(), e);
+		std::os::set_exit_status(125);
+	}
+}
+
+// Built with the gnu-compat feature, stdbuf behaves as GNU's without being
+// asked to; otherwise --gnu-compat, given first, selects the same.
+#[cfg(feature = "gnu-compat")]
+static GNU_COMPAT: bool = true;
+#[cfg(not(feature = "gnu-compat"))]
+static GNU_COMPAT: bool = false;
+
+// GNU stdbuf and nothing else: -i/-o/-e, --help and --version, options up to
+// the first operand only, GNU's messages on stderr, 125 for our own errors
+// and 126/127 when COMMAND cannot be run. It always execs COMMAND.
+fn gnu_fail(msg : String, hint : bool) {
+	let mut err = std::io::stdio::stderr();
+	let _ = writeln!(&mut err, "stdbuf: {}", msg);
+	if hint {
+		let _ = writeln!(&mut err, "Try 'stdbuf --help' for more information.");
+	}
+	std::os::set_exit_status(125);
+}
+
+fn gnu_main(args : &[String]) {
+	let mut env : Vec<(String, String)> = Vec::new();
+	let mut i = 0;
+	while i < args.len() {
+		let arg = args[i].as_slice();
+		if arg == "--" {
+			i += 1;
+			break;
+		}
+		if !arg.starts_with("-") || arg == "-" {
+			break;
+		}
+		match arg {
+			"--help" => {
+				println!("Usage: stdbuf OPTION... COMMAND\nRun COMMAND, with modified buffering operations for its standard streams.\n\n  -i, --input=MODE   adjust standard input stream buffering\n  -o, --output=MODE  adjust standard output stream buffering\n  -e, --error=MODE   adjust standard error stream buffering\n      --help     display this help and exit\n      --version  output version information and exit");
+				return;
+			},
+			"--version" => {
+				print_version();
+				return;
+			},
+			_ => {},
+		}
+		// "-oL", "-o L", "--output=L", "--output L"
+		let (stream, inline) = if arg.starts_with("--") {
+			let (name, value) = match arg.find('=') {
+				Some(idx) => (arg.slice(2, idx), Some(arg.slice_from(idx + 1))),
+				None => (arg.slice_from(2), None),
+			};
+			let stream = match ["input", "output", "error"].iter().find(|full| !name.is_empty() && full.starts_with(name)) {
+				Some(full) => full.char_at(0),
+				None => return gnu_fail(format!("unrecognized option '{}'", arg), true),
+			};
+			(stream, value)
+		} else {
+			let stream = arg.char_at(1);
+			if !"ioe".contains_char(stream) {
+				return gnu_fail(format!("invalid option -- '{}'", stream), true);
+			}
+			(stream, if arg.len() > 2 { Some(arg.slice_from(2)) } else { None })
+		};
+		let mode = match inline {
+			Some(mode) => mode.to_string(),
+			None => {
+				i += 1;
+				if i == args.len() {
+					return gnu_fail(format!("option requires an argument -- '{}'", stream), true);
+				}
+				args[i].clone()
+			},
+		};
+		let value = match from_str::<BufferType>(mode.as_slice()) {
+			Some(BufferType::Line) if stream == 'i' => return gnu_fail("line buffering stdin is meaningless".to_string(), true),
+			Some(mode) => mode.to_string(),
+			None => return gnu_fail(format!("invalid mode '{}'", mode), false),
+		};
+		let name = format!("_STDBUF_{}", stream.to_uppercase());
+		env.retain(|&(ref key, _)| *key != name);
+		env.push((name, value));
+		i += 1;
+	}
+	if i == args.len() {
+		return gnu_fail("missing operand".to_string(), true);
+	}
+	if env.is_empty() {
+		return gnu_fail("you must specify a buffering mode option".to_string(), true);
+	}
+	if !preload::already_preloaded() {
+		match preload::library() {
+			Ok(library) => env.push((preload::VARIABLE.to_string(), preload::value(&library))),
+			Err(_) => return gnu_fail("failed to find 'libstdbuf.so'".to_string(), false),
+		}
+	}
+	let e = exec(args[i].as_slice(), args.slice_from(i + 1), env.as_slice());
+	let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: failed to run command '{}': {}", args[i], e.detail.clone().unwrap_or(e.desc.to_string()));
+	std::os::set_exit_status(match e.kind {
+		FileNotFound => 127,
+		_ => 126,
+	});
+}
+
+// The options of `stdbuf OPTION... COMMAND`.
+fn option_groups() -> Vec<OptGroup> {
+	vec![
+		optopt("i", "input", "adjust standard input stream buffering", "MODE"),
+		optopt("o", "output", "adjust standard output stream buffering", "MODE"),
+		optopt("e", "error", "adjust standard error stream buffering", "MODE"),
+		optopt("a", "all", "adjust the buffering of every stream not set above (stdin stays as is for L)", "MODE"),
+		optopt("S", "split-string", "split STRING into options and COMMAND, as a #! line passes them in one argument", "STRING"),
+		optopt("", "audit-log", "append a record of every lifecycle event to FILE", "FILE"),
+		optflag("p", "pty", "run COMMAND on a pseudo-terminal, for programs that only line-buffer when isatty() says so"),
+		optmulti("", "pipe", "with --pty, keep some of stdin, stdout and stderr pipes, e.g. --pipe stderr", "STREAMS"),
+		optflag("", "no-foreground", "with --pty, do not make the terminal COMMAND's controlling terminal with it in the foreground"),
+		optflag("", "hold-stdin", "with --pty, keep COMMAND's input open once stdin ends, for --send-on or programs that quit on EOF"),
+		optopt("", "fake-isatty", "have isatty() in COMMAND say yes for the listed streams, e.g. stdout,stderr, without a pseudo-terminal", "STREAMS"),
+		optflag("", "coalesce-stdin", "pass stdin on to COMMAND in whole lines (-i L) or blocks (-i SIZE) rather than as it arrives"),
+		optmulti("", "fd", "set the buffering of a stream COMMAND opens on descriptor N after it has started, e.g. --fd 3:L", "N:MODE"),
+		optopt("", "all-streams", "set the buffering of every other stream COMMAND opens after it has started", "MODE"),
+		optopt("", "depth", "change only COMMAND and the programs it runs N-1 execs deep; 1 is COMMAND alone", "N"),
+		optopt("", "only-for", "change nothing in programs COMMAND runs unless their name matches GLOB, e.g. through a wrapper script", "GLOB"),
+		optflag("", "env-only", "set the _STDBUF_* variables but preload no libstdbuf, for one the system preloads already"),
+		optopt("", "trace-io", "log every write() and fwrite() COMMAND makes, with descriptor, size and time, to FILE", "FILE"),
+		optopt("", "flush-on", "pass output on a record at a time, each ended by BYTE (e.g. 0 for NUL) rather than by a newline", "BYTE"),
+		optopt("", "flush-interval", "pass on output held back by the relay at least this often, e.g. 250ms", "DURATION"),
+		optflag("", "term-size", "tell COMMAND the terminal's size in COLUMNS and LINES, for output that is piped"),
+		optflag("", "report-env", "if COMMAND fails, add its environment (redacted) to the audit log"),
+		optopt("", "landlock", "confine COMMAND to the given paths, e.g. ro:/usr,rw:/tmp (Linux only)", "RULES"),
+		optopt("", "drop-caps", "drop the listed capabilities (or 'all') before running COMMAND", "CAPS"),
+		optopt("", "oom-score-adj", "set the OOM killer score adjustment of COMMAND", "N"),
+		optopt("", "tee-fd", "also copy COMMAND's output to the already open descriptor N", "N"),
+		optopt("", "capture-format", "write the file capture as plain text (default) or as zstd-seekable, a frame every LINES lines (default 1000)", "FORMAT"),
+		optflag("", "merge", "pass COMMAND's stderr on with its stdout, like 2>&1, never inside a line of either"),
+		optopt("", "tee", "also copy COMMAND's output to FILE as it is passed on", "FILE"),
+		optflag("", "tee-err", "with --tee, copy COMMAND's stderr into the same file"),
+		optopt("", "stdout-file", "also copy COMMAND's output to a file named by TEMPLATE, which may use {cmd}, {date}, {pid} and {seq}", "TEMPLATE"),
+		optflagopt("", "dedup", "collapse runs of identical output lines, summarising at most every N repeats", "N"),
+		optopt("", "min-level", "drop output lines below LEVEL (trace, debug, info, warn, error)", "LEVEL"),
+		optmulti("", "level-regex", "classify lines matching REGEX as LEVEL for --min-level", "LEVEL=REGEX"),
+		optopt("", "group-by", "keep lines not matching REGEX together with the record they continue", "REGEX"),
+		optmulti("", "redact", "replace matches of REGEX in the output (default replacement [REDACTED])", "REGEX[=REPLACEMENT]"),
+		optflag("", "redact-secrets", "redact common tokens, keys and passwords in the output"),
+		optopt("", "progress-fd", "report relayed bytes and lines every second on the already open descriptor N", "N"),
+		optopt("", "progress-total", "draw a progress bar on standard error, N output lines being 100%", "N"),
+		optopt("", "progress-pattern", "count only the lines matching REGEX towards --progress-total", "REGEX"),
+		optopt("", "split-output", "write the -o MODE:FILE copy as FILE.001, FILE.002, ... of at most SIZE bytes", "SIZE"),
+		optopt("", "prefix", "start every output line with TEMPLATE; it may use {ts}, {ts:FORMAT}, {stream}, {pid}, {host} and {label}", "TEMPLATE"),
+		optflagopt("", "timestamp", "start every output line with the time, in strftime FORMAT (default as ts(1): %b %d %H:%M:%S)", "FORMAT"),
+		optopt("", "label", "value of {label} in --prefix", "LABEL"),
+		optopt("", "line-max", "cut displayed lines to at most N columns; file and descriptor copies stay complete", "N"),
+		optflag("", "notify-systemd", "pass COMMAND's sd_notify messages on to systemd"),
+		optopt("", "notify-ready", "tell systemd READY=1 once the output matches REGEX (implies --notify-systemd)", "REGEX"),
+		optopt("", "fake-clock", "take all times from a clock starting at START (epoch seconds or YYYY-MM-DDTHH:MM:SS) and running RATE times as fast", "START[:RATE]"),
+		optflag("", "watch", "supervise COMMAND while libstdbuf alone sets its buffering; its output is passed on untouched"),
+		optopt("", "heartbeat", "print relay statistics to stderr every DURATION", "DURATION"),
+		optopt("", "timeout", "stop COMMAND, as on SIGTERM, once it has run for DURATION; stdbuf then exits with 124", "DURATION"),
+		optopt("", "stats-signal", "print relay statistics to stderr when stdbuf receives SIG (default: USR1; 'none' to disable)", "SIG"),
+		optopt("", "stop-signal", "signal sent to COMMAND when stdbuf is told to terminate (default: the one received)", "SIG"),
+		optopt("", "stop-timeout", "kill COMMAND if it is still running this long after being told to stop", "DURATION"),
+		optflag("", "entrypoint", "container entrypoint mode: run $STDBUF_COMMAND when no COMMAND is given and reap orphans as PID 1"),
+		optopt("", "env-file", "add the KEY=VALUE lines of FILE to COMMAND's environment", "FILE"),
+		optflag("", "strip-ansi", "remove color and other terminal escape sequences from the output"),
+		optopt("", "filters", "run the output filters in this order, e.g. stdout=strip-ansi,redact,prefix", "[stdout=]LIST"),
+		optopt("", "filter-exec", "pass the output line by line through the shell command CMD, started once for the whole run", "CMD"),
+		optflag("", "raw", "relay the output byte for byte as it arrives; refuses every option that would change it"),
+		optflag("", "close-fds", "start COMMAND with only stdin, stdout and stderr open (default)"),
+		optflag("", "inherit-fds", "pass the descriptors above 2 that stdbuf was started with on to COMMAND"),
+		optopt("", "after", "once COMMAND has run this long, pass its output on in MODE instead, e.g. '30s:4M' after -o 0", "DURATION:MODE"),
+		optopt("", "capture-window", "copy only this part of the output to the -o file and --tee-fd, e.g. 'BEGIN RESULTS..END RESULTS' or '+10s..+1m'", "START[..END]"),
+		optopt("", "shape", "slow the relay down to test what reads it, e.g. delay=50ms,jitter=10ms,rate=1MBps", "SETTINGS"),
+		optmulti("", "on-stderr-pattern", "when a line of COMMAND's stderr matches PATTERN: kill it, restart it, mark-failed (exit 1) or run the shell command CMD", "PATTERN=ACTION"),
+		optmulti("", "service", "run COMMAND alongside the other services, its output prefixed with NAME; NAME:oMODE,eMODE sets its own modes", "NAME=COMMAND"),
+		optopt("", "service-logs", "also copy each service's output to DIR/NAME.N.log", "DIR"),
+		optmulti("", "send-on", "write RESPONSE to COMMAND's input whenever its output matches PATTERN", "PATTERN=RESPONSE"),
+		optflag("", "validate", "check the options and COMMAND, then exit without running it"),
+		optflag("", "verbose", "describe exactly what is executed on standard error"),
+		optflag("", "help", "display this help and exit"),
+		optflag("", "version", "output version information and exit"),
+	]
+}
+
+impl ProgramOptions {
+	// as if no option had been given
+	fn new() -> ProgramOptions {
+		ProgramOptions {
+			stdin : BufferType::Default,
+			stdout : BufferType::Default,
+			stderr : BufferType::Default,
+			audit_log : None,
+			landlock : None,
+			drop_caps : None,
+			oom_score_adj : None,
+			send_on : Vec::new(),
+			tee_fd : None,
+			validate : false,
+			verbose : false,
+			stdout_file : None,
+			stdout_template : None,
+			tee : None,
+			tee_err : false,
+			merge : false,
+			only_for : None,
+			depth : None,
+			env_only : false,
+			watch : false,
+			heartbeat : None,
+			timeout : None,
+			frame_lines : None,
+			dedup : false,
+			dedup_window : None,
+			min_level : None,
+			level_rules : Vec::new(),
+			group_by : None,
+			redact : Vec::new(),
+			progress_fd : None,
+			split_output : None,
+			prefix : None,
+			label : None,
+			line_max : None,
+			notify_systemd : false,
+			notify_ready : None,
+			fake_clock : None,
+			stop_signal : None,
+			stop_timeout : None,
+			stats_signal : Some(STATS_SIGNAL),
+			entrypoint : false,
+			env_file : None,
+			strip_ansi : false,
+			filter_order : None,
+			filter_exec : None,
+			raw : false,
+			inherit_fds : false,
+			capture_window : None,
+			after : None,
+			progress_total : None,
+			progress_pattern : None,
+			shape : None,
+			on_stderr : Vec::new(),
+			report_env : false,
+			pty : None,
+			pipe_given : false,
+			hold_stdin : false,
+			no_foreground : false,
+			term_size : false,
+			fake_isatty : None,
+			flush_interval : None,
+			flush_on : None,
+			trace_io : None,
+			fd_modes : Vec::new(),
+			all_streams : None,
+			coalesce_stdin : false,
+			services : Vec::new(),
+			service_logs : None,
+		}
+	}
+}
+
+fn main() {
+	let mut args = os::args();
+	match split_shebang(args.as_slice()) {
+		Ok(Some(split)) => args = split,
+		Ok(None) => {},
+		Err(e) => {
+			println!("stdbuf: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+	}
+	// the --pty child, on its way to becoming COMMAND
+	if args.len() > 2 && (args[1].as_slice() == "__pty-exec" || args[1].as_slice() == "__pty-exec-background") {
+		if let Err(e) = pty::claim_terminal(args[1].as_slice() == "__pty-exec") {
+			println!("stdbuf: cannot make the pseudo-terminal the controlling terminal: {}", e);
+		}
+		let e = exec(args[2].as_slice(), args.slice_from(3), &[]);
+		println!("stdbuf: exec {}: {}", args[2], e);
+		std::os::set_exit_status(match e.kind {
+			FileNotFound => 127,
+			_ => 126,
+		});
+		return;
+	}
+	if args.len() > 1 && args[1].as_slice() == "--gnu-compat" {
+		gnu_main(args.slice_from(2));
+		return;
+	}
+	if GNU_COMPAT {
+		gnu_main(args.slice_from(1));
+		return;
+	}
+	// the subcommand comes first, before any option
+	if args.len() > 1 && args[1].as_slice() == "cat" {
+		cat_main(args.slice_from(2));
+		return;
+	}
+	if args.len() > 1 && args[1].as_slice() == "tail" {
+		tail_main(args.slice_from(2));
+		return;
+	}
+	let optgrps = option_groups();
+	let mut options = ProgramOptions::new();
+	if let Ok(m) = getopts(args.slice_from(1), optgrps.as_slice()) {
+		if m.opt_present("entrypoint") && m.free.is_empty() {
+			if let Some(line) = os::getenv("STDBUF_COMMAND") {
+				match init::split_words(line.as_slice()) {
+					Ok(words) => args.extend(words.into_iter()),
+					Err(e) => {
+						println!("stdbuf: cannot parse STDBUF_COMMAND: {}", e);
+						std::os::set_exit_status(125);
+						return;
+					}
+				}
+			}
+		}
+	}
+	let mut command_idx = -1;
+	// Only the options ahead of the command are parsed, so nothing of its
+	// own can be taken for one of ours. Without a command all of them are,
+	// for --help and --version.
+	let end = command_index(args.as_slice(), optgrps.as_slice()).map_or(args.len(), |idx| idx + 1);
+	match parse_options(args.slice(1, end), &mut options, optgrps.as_slice()) {
+		Ok(OkMsg::Buffering) => command_idx = end - 1,
+		Ok(OkMsg::Help) => {
+			print_usage(optgrps.as_slice());
+			return;
+		},
+		Ok(OkMsg::Version) => {
+			print_version();
+			return;
+		},
+		// said why already, or left to diagnose() below
+		Err(ErrMsg::Fatal) | Err(ErrMsg::Invalid) => {},
+	}
+	if command_idx != -1 && !options.services.is_empty() {
+		let services = mem::replace(&mut options.services, Vec::new());
+		let (stdout, stderr) = (options.stdout.clone(), options.stderr.clone());
+		let mut envs = Vec::new();
+		for service in services.iter() {
+			options.stdout = stdout.clone();
+			options.stderr = stderr.clone();
+			for &(stream, ref mode) in service.modes.iter() {
+				if stream == 'o' {
+					options.stdout = mode.clone();
+				} else {
+					options.stderr = mode.clone();
+				}
+			}
+			match buffering_env(&options) {
+				Ok(env) => envs.push(env),
+				Err(e) => {
+					println!("stdbuf: {}", e);
+					std::os::set_exit_status(125);
+					return;
+				}
+			}
+		}
+		let code = service::run(services.as_slice(), envs.as_slice(), options.service_logs.as_ref());
+		std::os::set_exit_status(code);
+		return;
+	}
+	if command_idx == -1 {
+		match diagnose(args.slice_from(1), optgrps.as_slice()) {
+			Some(msg) => println!("stdbuf: {}", msg),
+			None => println!("Invalid options"),
+		}
+		println!("Try 'stdbuf --help' for more information.");
+		std::os::set_exit_status(125);
+		return;
+	}
+	let (run, runs) = if args[command_idx].as_slice() == "run" {
+		match parse_run(args.slice_from(command_idx + 1)) {
+			Ok((plan, runs)) => (Some(plan), runs),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	} else {
+		(None, vec![args.slice_from(command_idx).to_vec()])
+	};
+	for argv in runs.iter() {
+		if is_self(argv[0].as_slice(), args[0].as_slice()) {
+			println!("stdbuf: refusing to run stdbuf under itself ({}); pass all options to a single stdbuf", argv[0]);
+			std::os::set_exit_status(125);
+			return;
+		}
+	}
+	if options.validate {
+		for argv in runs.iter() {
+			if let Err(e) = validate(&options, argv[0].as_slice()) {
+				println!("stdbuf: {}", e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+		return;
+	}
+
+	if let Some((start, rate)) = options.fake_clock {
+		clock::set_fake(start, rate);
+	}
+	// taken before the audit log and the sinks add descriptors of our own
+	let inherited = if options.inherit_fds { open_descriptors() } else { Vec::new() };
+	let mut audit = match options.audit_log {
+		Some(ref path) => match AuditLog::open(path) {
+			Ok(log) => log,
+			Err(e) => {
+				println!("stdbuf: cannot open audit log {}: {}", path.display(), e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		},
+		None => AuditLog::disabled(),
+	};
+	let mut sinks = Sinks::new();
+	if let Some(fd) = options.tee_fd {
+		match open_inherited_fd(fd) {
+			Some(stream) => sinks.add("tee-fd".to_string(), box stream),
+			None => {
+				println!("stdbuf: descriptor {} given to --tee-fd is not open", fd);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	}
+	let progress_out = match options.progress_fd {
+		Some(fd) => match open_inherited_fd(fd) {
+			Some(stream) => Some(stream),
+			None => {
+				println!("stdbuf: descriptor {} given to --progress-fd is not open", fd);
+				std::os::set_exit_status(125);
+				return;
+			}
+		},
+		None => None,
+	};
+	if let Some(ref template) = options.stdout_template {
+		let cmd = Path::new(args[command_idx].as_slice()).filename_str().unwrap_or("command").to_string();
+		match sink::expand_name(template.as_slice(), cmd.as_slice()) {
+			Ok(path) => options.stdout_file = Some(path),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	}
+	if let Some(ref path) = options.stdout_file {
+		match (options.split_output, options.frame_lines) {
+			(Some(limit), _) => sinks.add(path.display().to_string(), box SplitFile::new(path.clone(), limit)),
+			(None, Some(lines)) => match SeekableFile::create(path, lines) {
+				Ok(file) => sinks.add(path.display().to_string(), box file),
+				Err(e) => {
+					println!("stdbuf: cannot open {}: {}", path.display(), e);
+					std::os::set_exit_status(125);
+					return;
+				}
+			},
+			(None, None) => match File::open_mode(path, Append, Write) {
+				Ok(file) => sinks.add(path.display().to_string(), box file),
+				Err(e) => {
+					println!("stdbuf: cannot open {}: {}", path.display(), e);
+					std::os::set_exit_status(125);
+					return;
+				}
+			},
+		}
+	}
+	let mut stderr_copy = None;
+	if let Some(ref path) = options.tee {
+		match File::open_mode(path, Append, Write) {
+			Ok(file) => {
+				let shared = SharedFile::new(file);
+				if options.tee_err {
+					stderr_copy = Some(shared.clone());
+				}
+				sinks.add(path.display().to_string(), box shared);
+			},
+			Err(e) => {
+				println!("stdbuf: cannot open {}: {}", path.display(), e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	}
+	audit.record("plan", &[
+		("command", args[command_idx].clone()),
+		("args", args.slice_from(command_idx+1).connect(" ")),
+		("stdin", format!("{}", options.stdin)),
+		("stdout", format!("{}", options.stdout)),
+		("stderr", format!("{}", options.stderr)),
+		("inherited-fds", inherited.iter().map(|fd| fd.to_string()).collect::<Vec<String>>().connect(",")),
+	]);
+
+	let mut oom_score = None;
+	if let Some(score) = options.oom_score_adj {
+		match OomScore::apply(score) {
+			Ok(applied) => oom_score = Some(applied),
+			Err(e) => {
+				audit.record("oom-score-adj-failed", &[("error", e.to_string())]);
+				println!("stdbuf: cannot set oom_score_adj to {}: {}", score, e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+		audit.record("oom-score-adj", &[("score", score.to_string())]);
+	}
+	if let Some(ref rules) = options.landlock {
+		if let Err(e) = landlock::restrict_self(rules) {
+			audit.record("landlock-failed", &[("error", e.clone())]);
+			println!("stdbuf: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+		audit.record("landlock", &[("rules", format!("{}", rules))]);
+	}
+	if let Some(ref set) = options.drop_caps {
+		if let Err(e) = caps::drop_self(set) {
+			audit.record("drop-caps-failed", &[("error", e.clone())]);
+			println!("stdbuf: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+		audit.record("drop-caps", &[("caps", set.names())]);
+	}
+
+	let mut buffering = match buffering_env(&options) {
+		Ok(env) => env,
+		Err(e) => {
+			println!("stdbuf: {}", e);
+			std::os::set_exit_status(125);
+			return;
+		}
+	};
+	if options.term_size {
+		buffering.extend(term_size_env().into_iter());
+	}
+	if buffering.iter().any(|&(ref key, _)| key.as_slice() == preload::VARIABLE) {
+		for argv in runs.iter() {
+			let protected = resolve_command(argv[0].as_slice()).and_then(|path| preload::stripped_by(&path));
+			if let Some(binary) = protected {
+				audit.record("preload-stripped", &[("command", argv[0].clone()), ("binary", binary.display().to_string())]);
+				let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: warning: {} is protected by System Integrity Protection, which removes {} before {} starts; its buffering stays unchanged", binary.display(), preload::VARIABLE, argv[0]);
+			}
+		}
+	}
+	// a static COMMAND ignores the preloaded library, so the modes are
+	// applied by the pipe pump instead, as on Windows
+	let static_runs : Vec<&Vec<String>> = if buffering.iter().any(|&(ref key, _)| key.as_slice() == preload::VARIABLE) {
+		runs.iter().filter(|argv| resolve_command(argv[0].as_slice()).map_or(false, |path| preload::statically_linked(&path))).collect()
+	} else {
+		Vec::new()
+	};
+	for argv in static_runs.iter() {
+		audit.record("pump-fallback", &[("command", argv[0].clone()), ("reason", "statically linked".to_string())]);
+		if options.fake_isatty.is_some() {
+			let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: warning: {} is statically linked, so --fake-isatty cannot reach it; use --pty", argv[0]);
+		}
+		if options.trace_io.is_some() {
+			let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: warning: {} is statically linked, so its writes cannot be traced", argv[0]);
+		}
+	}
+	let pumped = cfg!(windows) || !static_runs.is_empty();
+	let supervised = pumped || run.is_some() || needs_supervisor(&options);
+	if options.verbose {
+		for argv in static_runs.iter() {
+			let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: note: {} is statically linked and will not load libstdbuf; its stdout mode is applied as the output is passed on", argv[0]);
+		}
+		for argv in runs.iter() {
+			print_plan(argv[0].as_slice(), argv.slice_from(1), buffering.as_slice(), supervised, pumped);
+		}
+	}
+	if !supervised {
+		let mut env = buffering.clone();
+		if let Some(ref path) = options.env_file {
+			match init::read_env_file(path) {
+				Ok(vars) => env.extend(vars.into_iter()),
+				Err(e) => {
+					println!("stdbuf: {}", e);
+					std::os::set_exit_status(125);
+					return;
+				}
+			}
+		}
+		if !options.inherit_fds {
+			close_on_exec(open_descriptors().as_slice());
+		}
+		let e = exec(runs[0][0].as_slice(), runs[0].slice_from(1), env.as_slice());
+		println!("stdbuf: exec {}: {}", runs[0][0], e);
+		std::os::set_exit_status(match e.kind {
+			FileNotFound => 127,
+			_ => 126,
+		});
+		return;
+	}
+	// outside of systemd there is nobody to notify and nothing to do
+	let notifier = if options.notify_systemd {
+		match Notifier::from_env() {
+			Ok(n) => n,
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	} else {
+		None
+	};
+
+	// what COMMAND's environment gets on top of ours
+	let mut overrides = buffering.clone();
+	if let Some(ref path) = options.env_file {
+		match init::read_env_file(path) {
+			Ok(vars) => overrides.extend(vars.into_iter()),
+			Err(e) => {
+				println!("stdbuf: {}", e);
+				std::os::set_exit_status(125);
+				return;
+			}
+		}
+	}
+	if let Some(ref n) = notifier {
+		overrides.push(("NOTIFY_SOCKET".to_string(), n.child_socket.clone()));
+		n.forward();
+	}
+	shutdown::install(options.stop_signal, options.stop_timeout);
+	if let Some(signum) = options.stats_signal {
+		stats::install(signum as libc::c_int, runs.len());
+	}
+	let mut ticker = Ticker::new();
+	if let Some(interval) = options.heartbeat {
+		stats::heartbeat(interval, runs.len(), &mut ticker);
+	}
+	if let Some(timeout) = options.timeout {
+		shutdown::stop_after(timeout);
+	}
+	let mut r = Relay {
+		// unbuffered: whatever is relayed is on its way the moment it is written
+		stdout : Some(Pump::new(box std::io::stdio::stdout_raw(), pump_mode(&options, pumped))),
+		display : options.line_max.map(|max| Truncator::new(max)),
+		sinks : sinks,
+		progress : progress_out.map(|out| Progress::start(out, &mut ticker)),
+		bar : options.progress_total.map(|total| ProgressBar::new(total, options.progress_pattern.clone())),
+		shaper : options.shape.clone().map(|shape| Shaper::new(shape)),
+		ready_watch : match (&notifier, &options.notify_ready) {
+			(&Some(_), &Some(ref re)) => Some(Responder::new(vec![SendRule { pattern : re.clone(), response : String::new() }])),
+			_ => None,
+		},
+		notifier : notifier,
+		stderr_copy : stderr_copy,
+		merged : None,
+		oom_score : oom_score,
+	};
+	if options.merge {
+		r.merged = r.stdout.as_mut().map(|out| out.merge());
+	}
+	if let Some(ref out) = r.stdout {
+		shutdown::flush_on_panic(out.pending());
+	}
+	shutdown::flush_on_panic(r.sinks.pending());
+	// offsets count from here, the moment COMMAND is running
+	if let Some((ref start, ref end)) = options.capture_window {
+		r.sinks.set_window(CaptureWindow::new(start.clone(), end.clone()));
+	}
+	// COMMAND itself keeps the mode it was started with; only how we pass
+	// its output on changes
+	if let Some((after, ref mode)) = options.after {
+		if let Some(ref mut out) = r.stdout {
+			out.switch_after(after, pump_mode_of(mode));
+		}
+	}
+	if let Some(interval) = options.flush_interval {
+		if let Some(ref mut out) = r.stdout {
+			out.flush_every(interval, &mut ticker);
+		}
+	}
+	ticker.start();
+	let mut results = Vec::new();
+	let mut marked_failed = false;
+	let mut restart = false;
+	for argv in runs.iter() {
+		let outcome = run_command(argv.as_slice(), &options, overrides.as_slice(), inherited.as_slice(), &mut r, &mut audit);
+		marked_failed = marked_failed || outcome.marked_failed;
+		restart = outcome.restart;
+		results.push((argv.connect(" "), outcome.code));
+		let stop = restart || shutdown::received().is_some() || shutdown::timed_out() || r.stdout.is_none()
+			|| (outcome.code != 0 && !run.as_ref().map_or(false, |plan| plan.keep_going));
+		if stop {
+			break;
+		}
+	}
+	if let Some(ref mut truncator) = r.display {
+		let rest = truncator.finish();
+		if let Some(ref mut out) = r.stdout {
+			let _ = out.write(rest.as_slice());
+		}
+	}
+	if let Some(ref mut out) = r.stdout {
+		let _ = out.flush();
+	}
+	for (name, e) in r.sinks.finish().into_iter() {
+		audit.record("sink-error", &[("sink", name), ("error", e.to_string())]);
+	}
+	if let Some(p) = r.progress {
+		p.finish();
+	}
+	if let Some(b) = r.bar {
+		b.finish();
+	}
+	let mut status = match run {
+		Some(ref plan) => {
+			report_runs(results.as_slice(), runs.len(), &mut audit);
+			plan.status(results.as_slice())
+		},
+		// as GNU stdbuf, which becomes COMMAND: its exit code, 128+N if it
+		// was killed by signal N, 126/127 if it could not be run at all
+		None => results.last().map_or(0, |&(_, code)| code),
+	};
+	if marked_failed && status == 0 {
+		audit.record("marked-failed", &[]);
+		status = 1;
+	}
+	// as timeout(1)
+	if shutdown::timed_out() {
+		audit.record("timeout", &[]);
+		std::os::set_exit_status(124);
+		return;
+	}
+	if let Some(signum) = shutdown::received() {
+		audit.record("signal-forwarded", &[("signal", signum.to_string())]);
+		std::os::set_exit_status(128 + signum);
+		return;
+	}
+	std::os::set_exit_status(status);
+	// the whole run starts over, sinks and all, from a fresh stdbuf
+	if restart {
+		audit.record("restart", &[]);
+		let e = exec(args[0].as_slice(), args.slice_from(1), &[]);
+		let _ = writeln!(&mut std::io::stdio::stderr(), "stdbuf: cannot restart {}: {}", args[command_idx], e);
+		std::os::set_exit_status(125);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use super::{parse_size, parse_duration, parse_options, command_index, option_groups};
+	use super::{ProgramOptions, BufferType, OkMsg, relay, pump_mode_of, needs_supervisor, buffering_env};
+	use std::io::IoResult;
+	use std::sync::{Arc, Mutex};
+	use audit::AuditLog;
+	use pump::{Pump, Mode};
+	use sink::Sinks;
+	use preload;
+
+	static UNITS: [(&'static str, i64), ..5] = [("ms", 1), ("s", 1000), ("m", 60_000), ("h", 3_600_000), ("d", 86_400_000)];
+
+	// a fixed sequence of pseudo-random numbers, so a failure can be replayed
+	fn numbers(count : uint) -> Vec<i64> {
+		let mut state = 0x2545F4914F6CDD1Du64;
+		range(0, count).map(|_| {
+			state = state * 6364136223846793005 + 1442695040888963407;
+			(state >> 44) as i64
+		}).collect()
+	}
+
+	fn ms(n : i64) -> Option<Duration> {
+		Some(Duration::milliseconds(n))
+	}
+
+	// the options of a whole command line, as main() takes them apart
+	fn parse(args : &[&str]) -> Result<(ProgramOptions, Option<uint>), ()> {
+		let args : Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+		let groups = option_groups();
+		let idx = command_index(args.as_slice(), groups.as_slice());
+		let end = idx.map_or(args.len(), |idx| idx + 1);
+		let mut options = ProgramOptions::new();
+		match parse_options(args.slice(1, end), &mut options, groups.as_slice()) {
+			Ok(OkMsg::Buffering) => Ok((options, idx)),
+			_ => Err(()),
+		}
+	}
+
+	// what was written, still readable after the writer has been given away
+	#[deriving(Clone)]
+	struct Captured {
+		data : Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Writer for Captured {
+		fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+			self.data.lock().push_all(buf);
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn raw_relays_every_byte_unchanged() {
+		let mut data = Vec::new();
+		for round in range(0u, 4) {
+			for byte in range(0u, 256) {
+				data.push(((byte + round * 7) % 256) as u8);
+			}
+		}
+		data.push_all(b"no newline at the end\r");
+		for &size in [1u, 7, 255, 4096].iter() {
+			let out = Captured { data : Arc::new(Mutex::new(Vec::new())) };
+			let copy = Captured { data : Arc::new(Mutex::new(Vec::new())) };
+			let mut stdout = Some(Pump::new(box out.clone(), Mode::Unbuffered));
+			let mut sinks = Sinks::new();
+			sinks.add("copy".to_string(), box copy.clone());
+			let mut audit = AuditLog::disabled();
+			for chunk in data.as_slice().chunks(size) {
+				relay(chunk, &mut stdout, &mut None, &mut sinks, &mut audit);
+			}
+			assert!(stdout.is_some());
+			assert_eq!(*out.data.lock(), data);
+			assert_eq!(*copy.data.lock(), data);
+		}
+	}
+
+	#[test]
+	fn bare_optional_value_leaves_command_alone() {
+		for &option in ["--dedup", "--timestamp"].iter() {
+			let (_, idx) = parse(&["stdbuf", "-oL", option, "grep", "x"]).unwrap();
+			assert_eq!(idx, Some(3));
+		}
+		let (options, _) = parse(&["stdbuf", "-oL", "--dedup", "grep", "x"]).unwrap();
+		assert!(options.dedup);
+		assert_eq!(options.dedup_window, None);
+		let (options, _) = parse(&["stdbuf", "-oL", "--dedup=3", "grep", "x"]).unwrap();
+		assert_eq!(options.dedup_window, Some(3));
+	}
+
+	#[test]
+	fn raw_and_watch_refuse_what_rewrites_the_output() {
+		let rewriting = [
+			vec!["--strip-ansi"], vec!["--redact", "secret"], vec!["--redact-secrets"], vec!["--dedup=3"],
+			vec!["--min-level", "warn"], vec!["--group-by", "^\\S"], vec!["--prefix", "{pid} "],
+			vec!["--filter-exec", "cat"], vec!["--line-max", "80"],
+		];
+		for &untouched in ["--raw", "--watch"].iter() {
+			for option in rewriting.iter() {
+				let mut args = vec!["stdbuf", untouched, "-o0"];
+				args.push_all(option.as_slice());
+				args.push("cat");
+				assert!(parse(args.as_slice()).is_err(), "{} was accepted with {}", untouched, option);
+			}
+			assert!(parse(&["stdbuf", untouched, "-o0", "cat"]).is_ok());
+		}
+	}
+
+	#[test]
+	fn relay_options_keep_stdbuf_in_between() {
+		let (options, _) = parse(&["stdbuf", "-oL", "cat"]).unwrap();
+		assert!(cfg!(windows) || !needs_supervisor(&options));
+		for option in [vec!["--flush-interval", "250ms"], vec!["--heartbeat", "10s"], vec!["--after", "30s:4M"], vec!["--watch"]].iter() {
+			let mut args = vec!["stdbuf", "-oL"];
+			args.push_all(option.as_slice());
+			args.push("cat");
+			let (options, _) = parse(args.as_slice()).unwrap();
+			assert!(needs_supervisor(&options), "{} would exec COMMAND", option);
+		}
+	}
+
+	#[test]
+	fn terminal_options_need_pty() {
+		for &option in ["--hold-stdin", "--no-foreground"].iter() {
+			assert!(parse(&["stdbuf", "-oL", option, "cat"]).is_err(), "{} was accepted without --pty", option);
+			assert!(parse(&["stdbuf", "-oL", "--pty", option, "cat"]).is_ok());
+		}
+	}
+
+	#[test]
+	fn env_only_leaves_out_the_preload() {
+		let (options, _) = parse(&["stdbuf", "-oL", "--env-only", "cat"]).unwrap();
+		let env = buffering_env(&options).unwrap();
+		assert!(env.iter().any(|&(ref key, ref value)| key.as_slice() == "_STDBUF_O" && value.as_slice() == "L"));
+		assert!(!env.iter().any(|&(ref key, _)| key.as_slice() == preload::VARIABLE));
+	}
+
+	#[test]
+	fn zero_size_is_unbuffered() {
+		for &spec in ["0", "0K", "00", "0b", "0.5", "0x4K"].iter() {
+			let mode = from_str::<BufferType>(spec);
+			assert!(mode == Some(BufferType::Unbuffered), "{} parsed as {}", spec, mode);
+			assert_eq!(pump_mode_of(&mode.unwrap()), Mode::Unbuffered);
+		}
+		// refused as -i 0 is, rather than left to divide by zero later
+		assert!(parse(&["stdbuf", "--coalesce-stdin", "-i", "0K", "cat"]).is_err());
+		assert!(parse(&["stdbuf", "--line-max", "80", "-o", "0K", "cat"]).is_ok());
+	}
+
+	#[test]
+	fn option_value_spellings() {
+		let modes = [("L", BufferType::Line), ("0", BufferType::Unbuffered), ("4K", BufferType::Size(4096)), ("1MB", BufferType::Size(1_000_000))];
+		for &(short, long) in [("-i", "--input"), ("-o", "--output"), ("-e", "--error")].iter() {
+			for &(value, ref mode) in modes.iter() {
+				let attached = format!("{}{}", short, value);
+				let joined = format!("{}={}", long, value);
+				let spellings = [
+					vec!["stdbuf", attached.as_slice(), "cat"],
+					vec!["stdbuf", short, value, "cat"],
+					vec!["stdbuf", joined.as_slice(), "cat"],
+					vec!["stdbuf", long, value, "cat"],
+				];
+				for args in spellings.iter() {
+					let (options, idx) = parse(args.as_slice()).unwrap();
+					let given = match short {
+						"-i" => &options.stdin,
+						"-o" => &options.stdout,
+						_ => &options.stderr,
+					};
+					assert_eq!(given, mode);
+					assert_eq!(idx, Some(args.len() - 1));
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn spellings_mixed() {
+		let (options, idx) = parse(&["stdbuf", "-i0", "--output", "L", "-e", "4K", "--", "cat"]).unwrap();
+		assert_eq!(options.stdin, BufferType::Unbuffered);
+		assert_eq!(options.stdout, BufferType::Line);
+		assert_eq!(options.stderr, BufferType::Size(4096));
+		assert_eq!(idx, Some(7));
+	}
+
+	#[test]
+	fn command_options_are_its_own() {
+		let (options, idx) = parse(&["stdbuf", "-oL", "grep", "-o", "0", "-e", "x"]).unwrap();
+		assert_eq!(options.stdout, BufferType::Line);
+		assert_eq!(options.stderr, BufferType::Default);
+		assert_eq!(idx, Some(2));
+	}
+
+	#[test]
+	fn invalid_values() {
+		for args in [&["stdbuf", "-oX", "cat"], &["stdbuf", "--output=", "cat"], &["stdbuf", "-o", "cat"]].iter() {
+			assert!(parse(*args).is_err());
+		}
+	}
+
+	#[test]
+	fn duration_every_unit() {
+		for n in numbers(200).into_iter() {
+			for &(unit, scale) in UNITS.iter() {
+				assert_eq!(parse_duration(format!("{}{}", n, unit).as_slice()), ms(n * scale));
+			}
+		}
+	}
+
+	#[test]
+	fn duration_plain_number_is_seconds() {
+		for n in numbers(200).into_iter() {
+			assert_eq!(parse_duration(n.to_string().as_slice()), ms(n * 1000));
+		}
+		assert_eq!(parse_duration("0"), ms(0));
+	}
+
+	#[test]
+	fn duration_compound_is_the_sum() {
+		let values = numbers(60);
+		for pair in values.as_slice().chunks(2) {
+			let (a, b) = (pair[0], pair[1]);
+			for &(first, first_scale) in UNITS.iter() {
+				for &(second, second_scale) in UNITS.iter() {
+					let spec = format!("{}{}{}{}", a, first, b, second);
+					assert_eq!(parse_duration(spec.as_slice()), ms(a * first_scale + b * second_scale));
+				}
+			}
+		}
+		assert_eq!(parse_duration("1h30m"), ms(5_400_000));
+		assert_eq!(parse_duration("1d2h3m4s5ms"), ms(86_400_000 + 7_200_000 + 180_000 + 4_000 + 5));
+	}
+
+	#[test]
+	fn duration_fractions() {
+		assert_eq!(parse_duration("1.5s"), ms(1500));
+		assert_eq!(parse_duration("1.5m"), ms(90_000));
+		assert_eq!(parse_duration("0.25"), ms(250));
+		assert_eq!(parse_duration("2.5ms"), ms(2));
+	}
+
+	#[test]
+	fn duration_invalid() {
+		for value in ["", "ms", "s", "-1s", "1x", "1 s", "1S", "1.2.3s", "1h30", "s1", "1sec", "1m-1s"].iter() {
+			assert_eq!(parse_duration(*value), None);
+		}
+	}
+
+	#[test]
+	fn size_without_suffix() {
+		assert_eq!(parse_size("0"), Ok(0));
+		assert_eq!(parse_size("1"), Ok(1));
+		assert_eq!(parse_size("4096"), Ok(4096));
+		assert_eq!(parse_size("18446744073709551615"), Ok(18446744073709551615));
+	}
+
+	#[test]
+	fn size_suffixes_as_gnu() {
+		let letters = "KMGTPE";
+		for (i, letter) in letters.chars().enumerate() {
+			let power = i + 1;
+			let binary = range(0, power).fold(1u64, |n, _| n * 1024);
+			let decimal = range(0, power).fold(1u64, |n, _| n * 1000);
+			let lower = letter.to_lowercase();
+			assert_eq!(parse_size(format!("1{}", letter).as_slice()), Ok(binary));
+			assert_eq!(parse_size(format!("1{}", lower).as_slice()), Ok(binary));
+			assert_eq!(parse_size(format!("1{}iB", letter).as_slice()), Ok(binary));
+			assert_eq!(parse_size(format!("1{}iB", lower).as_slice()), Ok(binary));
+			assert_eq!(parse_size(format!("1{}B", letter).as_slice()), Ok(decimal));
+			assert_eq!(parse_size(format!("1{}B", lower).as_slice()), Ok(decimal));
+			assert_eq!(parse_size(format!("1{}b", letter).as_slice()), Ok(decimal));
+			assert_eq!(parse_size(format!("3{}", letter).as_slice()), Ok(3 * binary));
+			assert_eq!(parse_size(format!("3{}B", letter).as_slice()), Ok(3 * decimal));
+		}
+	}
+
+	#[test]
+	fn size_blocks() {
+		assert_eq!(parse_size("1b"), Ok(512));
+		assert_eq!(parse_size("8b"), Ok(4096));
+		assert_eq!(parse_size("0b"), Ok(0));
+	}
+
+	#[test]
+	fn size_fractions() {
+		assert_eq!(parse_size("1.5K"), Ok(1536));
+		assert_eq!(parse_size("0.5M"), Ok(512 * 1024));
+		assert_eq!(parse_size(".5K"), Ok(512));
+		assert_eq!(parse_size("1.5KB"), Ok(1500));
+		// fractions of a byte are dropped
+		assert_eq!(parse_size("1.7"), Ok(1));
+	}
+
+	#[test]
+	fn size_products() {
+		assert_eq!(parse_size("64x1K"), Ok(64 * 1024));
+		assert_eq!(parse_size("2x3x4"), Ok(24));
+		assert_eq!(parse_size("2bx2"), Ok(2048));
+	}
+
+	#[test]
+	fn size_too_large() {
+		assert!(parse_size("18446744073709551616").is_err());
+		assert!(parse_size("16E").is_err());
+		assert!(parse_size("1Z").is_err());
+		assert!(parse_size("1Y").is_err());
+		assert!(parse_size("1ZB").is_err());
+		assert!(parse_size("4Gx4Gx4G").is_err());
+		assert_eq!(parse_size("15E"), Ok(15 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024));
+	}
+
+	#[test]
+	fn size_invalid() {
+		for size in ["", "K", "x", "1x", "x1", "1.2.3", "1Q", "1KiBB", "1Kib", "1iB", "1B", "-1", " 1", "1 K", "L", "1.K."].iter() {
+			assert!(parse_size(*size).is_err(), "'{}' was accepted", size);
+		}
+	}
+}
@@ -0,0 +1,107 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// `stdbuf tail`: the end of a capture, and with -f whatever is added to it
+// from then on. A capture cut by --split-output is followed across its
+// parts: once FILE.(N+1) appears, FILE.N is read to its end and FILE.(N+1)
+// is followed instead, so a long-running supervised service can be watched
+// without noticing where one part stops and the next begins.
+
+use std::io::{File, IoResult, EndOfFile, FileNotFound, standard_error};
+use std::io::fs::PathExtensions;
+use std::io::timer::sleep;
+use std::time::Duration;
+use sink::part_name;
+
+// how often a followed capture is checked for more output
+static POLL_MS: i64 = 250;
+
+// The file currently written: the highest numbered part of a split
+// capture, or FILE itself. Returns the part number, 0 for FILE.
+fn current(base : &Path) -> Option<(Path, uint)> {
+	let mut last = None;
+	let mut part = 1;
+	loop {
+		let path = part_name(base, part);
+		if !path.is_file() {
+			break;
+		}
+		last = Some((path, part));
+		part += 1;
+	}
+	match last {
+		Some(found) => Some(found),
+		None if base.is_file() => Some((base.clone(), 0)),
+		None => None,
+	}
+}
+
+// Copies whatever can be read now, up to the current end of the file.
+fn drain(file : &mut File, out : &mut Writer) -> IoResult<()> {
+	let mut buf = [0u8, ..4096];
+	loop {
+		match file.read(&mut buf) {
+			Ok(n) => try!(out.write(buf.slice_to(n))),
+			Err(ref e) if e.kind == EndOfFile => return Ok(()),
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+// The last `lines` lines of the current part; earlier parts are not looked
+// at even if it holds fewer.
+pub fn tail(base : &Path, lines : uint, follow : bool, out : &mut Writer) -> IoResult<()> {
+	let (path, mut part) = match current(base) {
+		Some(found) => found,
+		None => return Err(standard_error(FileNotFound)),
+	};
+	let mut file = try!(File::open(&path));
+	let data = try!(file.read_to_end());
+	let mut start = data.len();
+	let mut seen = 0;
+	// a final newline ends the last line rather than starting another
+	let end = if data.last() == Some(&b'\n') { data.len() - 1 } else { data.len() };
+	for i in range(0, end).rev() {
+		if data[i] == b'\n' {
+			seen += 1;
+			if seen == lines {
+				break;
+			}
+		}
+		start = i;
+	}
+	if lines == 0 {
+		start = data.len();
+	}
+	try!(out.write(data.slice_from(start)));
+	try!(out.flush());
+	if !follow {
+		return Ok(());
+	}
+	loop {
+		try!(drain(&mut file, out));
+		try!(out.flush());
+		// the part is complete once the next one exists, but may have been
+		// written to in between
+		let next = part_name(base, part + 1);
+		if part > 0 && next.is_file() {
+			try!(drain(&mut file, out));
+			file = try!(File::open(&next));
+			part += 1;
+			continue;
+		}
+		sleep(Duration::milliseconds(POLL_MS));
+		// a split capture may have started after we looked
+		if part == 0 && part_name(base, 1).is_file() {
+			try!(drain(&mut file, out));
+			file = try!(File::open(&part_name(base, 1)));
+			part = 1;
+		}
+	}
+}
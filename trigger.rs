@@ -0,0 +1,139 @@
+/*
+ * This file is part of the uutils coreutils package.
+ *
+ * (c) Dorota Kapturkiewicz <dokaptur@gmail.com>
+ *
+ * For the full copyright and license information, please view the LICENSE
+ * file that was distributed with this source code.
+ */
+
+// --on-stderr-pattern: COMMAND's stderr is passed through line by line and
+// watched for markers of a failure it will never recover from, such as a
+// deadlock report or an OOM message from a runtime that keeps going.
+
+use std::io::{BufferedReader, Command};
+use std::io::pipe::PipeStream;
+use std::io::process::{InheritFd, Ignored};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{Thread, JoinGuard};
+use libc::pid_t;
+use regex::Regex;
+use filter::Pipeline;
+use shutdown;
+use sink::SharedFile;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Action {
+	Kill,
+	Restart,
+	MarkFailed,
+	Exec(String),
+}
+
+#[deriving(Show, Clone)]
+pub struct Trigger {
+	pub pattern : Regex,
+	pub action : Action,
+}
+
+impl Trigger {
+	// "PATTERN=ACTION", split at the first '=' as for --send-on
+	pub fn parse(spec : &str) -> Result<Trigger, String> {
+		let idx = match spec.find('=') {
+			Some(idx) => idx,
+			None => return Err(format!("invalid --on-stderr-pattern rule '{}', expected PATTERN=ACTION", spec)),
+		};
+		let pattern = match Regex::new(spec.slice_to(idx)) {
+			Ok(re) => re,
+			Err(e) => return Err(format!("invalid --on-stderr-pattern pattern '{}': {}", spec.slice_to(idx), e)),
+		};
+		let action = match spec.slice_from(idx + 1) {
+			"kill" => Action::Kill,
+			"restart" => Action::Restart,
+			"mark-failed" => Action::MarkFailed,
+			action if action.starts_with("exec:") && action.len() > 5 => Action::Exec(action.slice_from(5).to_string()),
+			action => return Err(format!("invalid --on-stderr-pattern action '{}', expected kill, restart, mark-failed or exec:CMD", action)),
+		};
+		Ok(Trigger { pattern : pattern, action : action })
+	}
+}
+
+struct Outcome {
+	failed : AtomicBool,
+	restart : AtomicBool,
+}
+
+pub struct StderrWatch {
+	outcome : Arc<Outcome>,
+	relay : JoinGuard<()>,
+}
+
+impl StderrWatch {
+	// Every line goes through `filters`, then on to `out`, our own stderr
+	// or with --merge our stdout, and to the --tee-err copy, before its
+	// actions run, so a marker that kills COMMAND is still there to be read.
+	// The patterns are matched against the line as COMMAND wrote it.
+	pub fn start(stderr : PipeStream, triggers : Vec<Trigger>, child : pid_t, filters : Pipeline, out : Box<Writer + Send>, copy : Option<SharedFile>) -> StderrWatch {
+		let outcome = Arc::new(Outcome { failed : AtomicBool::new(false), restart : AtomicBool::new(false) });
+		let shared = outcome.clone();
+		let relay = Thread::spawn(move || {
+			let mut reader = BufferedReader::new(stderr);
+			let mut filters = filters;
+			let mut out = out;
+			let mut copy = copy;
+			while let Ok(line) = reader.read_until(b'\n') {
+				pass_on(filters.push(line.as_slice()).as_slice(), &mut out, &mut copy);
+				let text = String::from_utf8_lossy(line.as_slice()).into_owned();
+				for trigger in triggers.iter().filter(|t| t.pattern.is_match(text.as_slice())) {
+					run(&trigger.action, &*shared, child);
+				}
+			}
+			pass_on(filters.finish().as_slice(), &mut out, &mut copy);
+		});
+		StderrWatch { outcome : outcome, relay : relay }
+	}
+
+	// waits until COMMAND's stderr is closed and everything was passed on
+	pub fn finish(self) -> (bool, bool) {
+		let _ = self.relay.join();
+		(self.outcome.failed.load(Ordering::SeqCst), self.outcome.restart.load(Ordering::SeqCst))
+	}
+}
+
+fn pass_on(lines : &[Vec<u8>], out : &mut Box<Writer + Send>, copy : &mut Option<SharedFile>) {
+	for line in lines.iter() {
+		let _ = out.write(line.as_slice());
+		// a failing copy is given up on, as the relay does with sinks
+		let failed = match *copy {
+			Some(ref mut file) => file.write(line.as_slice()).is_err(),
+			None => false,
+		};
+		if failed {
+			*copy = None;
+		}
+	}
+}
+
+fn run(action : &Action, outcome : &Outcome, child : pid_t) {
+	match *action {
+		Action::Kill => shutdown::stop_child(),
+		Action::Restart => {
+			outcome.restart.store(true, Ordering::SeqCst);
+			shutdown::stop_child();
+		},
+		Action::MarkFailed => outcome.failed.store(true, Ordering::SeqCst),
+		// runs beside COMMAND, which it can find in $STDBUF_CHILD_PID
+		Action::Exec(ref cmd) => {
+			let spawned = Command::new("sh").arg("-c").arg(cmd.as_slice())
+				.env("STDBUF_CHILD_PID", child.to_string())
+				.stdin(Ignored)
+				.stdout(InheritFd(2))
+				.stderr(InheritFd(2))
+				.spawn();
+			if let Ok(mut process) = spawned {
+				Thread::spawn(move || { let _ = process.wait(); }).detach();
+			}
+		},
+	}
+}